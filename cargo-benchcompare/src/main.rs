@@ -0,0 +1,129 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+
+use clap::Parser;
+use ussal_archive::compare::{compare, Verdict};
+use ussal_archive::query::{KeyPredicate, Query};
+use ussal_archive::{BenchArchive, BenchMeasurement};
+
+/// Invoked by cargo as `cargo-benchcompare benchcompare ...`
+#[derive(Parser, Debug)]
+#[clap(bin_name = "cargo")]
+enum Cargo {
+    Benchcompare(Args),
+}
+
+/// Compares the latest measurements in two bench archives.
+/// Exits with 1 when a regression is found.
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// The archive to compare against e.g. the main branch bench_history.cbor
+    old: String,
+
+    /// The archive containing the changes to check e.g. the bench.cbor of a PR
+    new: String,
+
+    /// Changes smaller than this percentage are not reported.
+    /// Changes must also exceed the noise band of the measurements to be reported.
+    #[clap(long, default_value_t = 5.0)]
+    threshold: f32,
+
+    /// Only compare benches with names matching this glob, e.g. `vec3 *`
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Only compare benches with this key set to this value, e.g. `machine=aarch64-linux-rpi4`.
+    /// Can be given multiple times.
+    #[clap(long, value_parser = parse_key)]
+    key: Vec<(String, String)>,
+
+    /// Compare against the baseline of this name pinned in `old`, e.g. `v1.0`, rather than its latest measurements
+    #[clap(long)]
+    baseline: Option<String>,
+}
+
+fn parse_key(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+        None => Err(format!("expected key=value but was {text:?}")),
+    }
+}
+
+fn main() {
+    std::process::exit(run());
+}
+
+fn run() -> i32 {
+    let Cargo::Benchcompare(args) = Cargo::parse();
+
+    let load = |path: &str| match BenchArchive::load(path) {
+        Ok(archive) => Some(archive),
+        Err(err) => {
+            eprintln!("Failed to load archive: {err:?}");
+            None
+        }
+    };
+    let (Some(old), Some(new)) = (load(&args.old), load(&args.new)) else {
+        return 2;
+    };
+    let old = match &args.baseline {
+        Some(name) => match old.baseline_archive(name) {
+            Some(baseline) => baseline,
+            None => {
+                eprintln!("{:?} has no baseline named {name:?}", args.old);
+                return 2;
+            }
+        },
+        None => old,
+    };
+
+    let mut query = Query::default();
+    if let Some(name) = &args.name {
+        query = query.with_name_glob(name);
+    }
+    for (key, value) in &args.key {
+        query = query.with_key(key, KeyPredicate::Equals(value.clone()));
+    }
+
+    let mut regressions = 0;
+    for comparison in compare(&old, &new, &query) {
+        let verdict = comparison.verdict(args.threshold / 100.0);
+        let change = match comparison.change() {
+            Some(change) => format!("{:+.2}%", change * 100.0),
+            None => "-".to_owned(),
+        };
+        let noise = match comparison.noise() {
+            Some(noise) => format!("±{:.2}%", noise * 100.0),
+            None => String::new(),
+        };
+        let value = |measurement: Option<&BenchMeasurement>| {
+            measurement.map_or("-".to_owned(), |x| x.value.to_string())
+        };
+        let flag = match verdict {
+            Verdict::Regressed => "REGRESSED",
+            Verdict::Improved => "improved",
+            Verdict::Unchanged => "",
+            Verdict::Added => "added",
+            Verdict::Removed => "removed",
+        };
+        println!(
+            "{:<24} {:<40} {:>12} -> {:<12} {:>9} {:<9} {flag}",
+            comparison.name,
+            comparison.keys_string(),
+            value(comparison.old),
+            value(comparison.new),
+            change,
+            noise,
+        );
+        if verdict == Verdict::Regressed {
+            regressions += 1;
+        }
+    }
+
+    if regressions > 0 {
+        eprintln!("{regressions} regressions found");
+        1
+    } else {
+        0
+    }
+}
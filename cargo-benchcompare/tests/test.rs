@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use subprocess::{Exec, ExitStatus, Redirection};
+use ussal_archive::{Bench, BenchArchive, BenchMeasurement};
+
+#[test]
+fn test_unchanged() {
+    let dir = fixtures("unchanged", 100.0, 102.0);
+    let (status, output) = benchcompare(&[path(&dir, "old"), path(&dir, "new")]);
+    assert_eq!(status, ExitStatus::Exited(0), "output was:\n{output}");
+    assert!(output.contains("mat4 mul vec4"), "output was:\n{output}");
+}
+
+#[test]
+fn test_improvement() {
+    let dir = fixtures("improvement", 100.0, 80.0);
+    let (status, output) = benchcompare(&[path(&dir, "old"), path(&dir, "new")]);
+    assert_eq!(status, ExitStatus::Exited(0), "output was:\n{output}");
+    assert!(output.contains("improved"), "output was:\n{output}");
+}
+
+#[test]
+fn test_regression() {
+    let dir = fixtures("regression", 100.0, 120.0);
+    let (status, output) = benchcompare(&[path(&dir, "old"), path(&dir, "new")]);
+    assert_eq!(status, ExitStatus::Exited(1), "output was:\n{output}");
+    assert!(output.contains("REGRESSED"), "output was:\n{output}");
+    assert!(
+        output.contains("1 regressions found"),
+        "output was:\n{output}"
+    );
+
+    // The regression is ignored when it is below the threshold
+    let (status, output) = benchcompare(&[
+        path(&dir, "old"),
+        path(&dir, "new"),
+        "--threshold".to_owned(),
+        "25".to_owned(),
+    ]);
+    assert_eq!(status, ExitStatus::Exited(0), "output was:\n{output}");
+}
+
+#[test]
+fn test_load_failure() {
+    let dir = fixtures("load-failure", 100.0, 100.0);
+    let missing = dir.join("missing.cbor").to_str().unwrap().to_owned();
+    let (status, output) = benchcompare(&[path(&dir, "old"), missing]);
+    assert_eq!(status, ExitStatus::Exited(2), "output was:\n{output}");
+    assert!(
+        output.contains("Failed to load archive"),
+        "output was:\n{output}"
+    );
+
+    let (status, output) = benchcompare(&[
+        path(&dir, "old"),
+        path(&dir, "new"),
+        "--baseline".to_owned(),
+        "v1.0".to_owned(),
+    ]);
+    assert_eq!(status, ExitStatus::Exited(2), "output was:\n{output}");
+    assert!(
+        output.contains("has no baseline named \"v1.0\""),
+        "output was:\n{output}"
+    );
+}
+
+/// Writes `old.cbor` and `new.cbor` archives, each containing a single measurement of the same series, to a fresh directory
+fn fixtures(name: &str, old: f32, new: f32) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargo-benchcompare-test-{name}"));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    for (file, value) in [("old", old), ("new", new)] {
+        let bench = Bench::new(
+            "mat4 mul vec4".to_owned(),
+            HashMap::from([
+                ("machine".to_owned(), "x86".to_owned()),
+                ("type".to_owned(), "walltime (ns)".to_owned()),
+            ]),
+            vec![BenchMeasurement::new(value)],
+        );
+        BenchArchive::new(file.to_owned(), vec![bench])
+            .save(&path(&dir, file))
+            .unwrap();
+    }
+    dir
+}
+
+fn path(dir: &std::path::Path, file: &str) -> String {
+    dir.join(format!("{file}.cbor"))
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Runs cargo-benchcompare the way cargo invokes it, returning its exit status and its combined stdout and stderr
+fn benchcompare(args: &[String]) -> (ExitStatus, String) {
+    let data = Exec::cmd(env!("CARGO_BIN_EXE_cargo-benchcompare"))
+        .arg("benchcompare")
+        .args(args)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Merge)
+        .capture()
+        .unwrap();
+    (data.exit_status, data.stdout_str())
+}
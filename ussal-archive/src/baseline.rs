@@ -0,0 +1,60 @@
+//! Named snapshots of an archive, e.g. `v1.0` or `before-refactor`, that later runs can be compared against to track long lived performance goals.
+
+use crate::{Bench, BenchArchive};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Baseline {
+    pub name: String,
+    /// When the baseline was pinned, in seconds since the unix epoch
+    pub pinned_at: Option<i64>,
+    /// The latest measurement of every series that was still being run when the baseline was pinned.
+    /// Kept separately from the history so that it survives retention.
+    pub benches: Vec<Bench>,
+}
+
+impl BenchArchive {
+    /// Pins the latest measurement of every series that is not retired as the baseline `name`.
+    /// Replaces any existing baseline of the same name.
+    pub fn pin_baseline(&mut self, name: &str, now: i64) {
+        let benches = self
+            .benches
+            .iter()
+            .filter(|bench| !bench.retired)
+            .filter_map(|bench| {
+                let measurement = bench.measurements.last()?.clone();
+                Some(Bench::new(
+                    bench.name.clone(),
+                    bench.keys.clone(),
+                    vec![measurement],
+                ))
+            })
+            .collect();
+        self.remove_baseline(name);
+        self.baselines.push(Baseline {
+            name: name.to_owned(),
+            pinned_at: Some(now),
+            benches,
+        });
+    }
+
+    /// Returns false if there was no baseline named `name`
+    pub fn remove_baseline(&mut self, name: &str) -> bool {
+        let len = self.baselines.len();
+        self.baselines.retain(|baseline| baseline.name != name);
+        self.baselines.len() != len
+    }
+
+    pub fn baseline(&self, name: &str) -> Option<&Baseline> {
+        self.baselines.iter().find(|baseline| baseline.name == name)
+    }
+
+    /// The baseline `name` as an archive of its own, for use with `compare::compare` and `report::markdown`
+    pub fn baseline_archive(&self, name: &str) -> Option<BenchArchive> {
+        let baseline = self.baseline(name)?;
+        Some(
+            BenchArchive::new(self.title.clone(), baseline.benches.clone())
+                .with_reset_id(self.reset_id),
+        )
+    }
+}
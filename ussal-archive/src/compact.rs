@@ -0,0 +1,279 @@
+//! A compact encoding for histories that are served to the web viewer.
+//!
+//! Plain cbor repeats every field name for every measurement, and every bench name, key and commit for every series.
+//! The compact encoding instead:
+//! * stores each distinct string once and refers to it by index
+//! * stores the values of each series as a column, each xored with the previous value so that the shared sign and exponent bits compress away
+//! * encodes struct fields by index rather than by name, i.e. packed cbor
+//! * compresses the result with deflate, which unlike zstd has a pure rust implementation that builds for wasm without a C toolchain
+//!
+//! Encoded archives start with `MAGIC` so they can be told apart from plain cbor archives, which `BenchArchive::load` still reads.
+//! Only the current version is written in this encoding, so when the archive format changes `decode` must learn to read the previous layout.
+
+use crate::baseline::Baseline;
+use crate::error::{ArchiveError, Result};
+use crate::regression::Finding;
+use crate::{Bench, BenchArchive, BenchMeasurement, BenchStats, RunMetadata, CURRENT_VERSION};
+use miniz_oxide::inflate::TINFLStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A plain cbor archive starts with a map, while this starts with a text string.
+pub(crate) const MAGIC: &[u8] = b"ussal-compact\0";
+
+/// The viewer decodes archives fetched over HTTP, so a small archive that decompresses to a huge size must not be able
+/// to exhaust its memory. This is far larger than any real history.
+const MAX_DECOMPRESSED_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct CompactArchive {
+    version: u32,
+    reset_id: u32,
+    title: String,
+    strings: Vec<String>,
+    benches: Vec<CompactBench>,
+    /// Added in version 4
+    #[serde(default)]
+    baselines: Vec<CompactBaseline>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactBaseline {
+    name: String,
+    pinned_at: Option<i64>,
+    benches: Vec<CompactBench>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactBench {
+    name: u32,
+    keys: Vec<(u32, u32)>,
+    /// The bits of each measurement's value xored with the bits of the previous measurement's value
+    values: Vec<u32>,
+    measurements: Vec<CompactMeasurement>,
+    missed_runs: u32,
+    retired: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactMeasurement {
+    samples: Option<Vec<f32>>,
+    stats: Option<BenchStats>,
+    metadata: Option<CompactMetadata>,
+    finding: Option<Finding>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactMetadata {
+    commit: Option<u32>,
+    branch: Option<u32>,
+    commit_time: Option<i64>,
+    run_time: Option<i64>,
+    toolchain: Option<u32>,
+    runner: Option<u32>,
+}
+
+#[derive(Default)]
+struct Interner {
+    strings: Vec<String>,
+    indexes: HashMap<String, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, string: &str) -> u32 {
+        if let Some(index) = self.indexes.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(string.to_owned());
+        self.indexes.insert(string.to_owned(), index);
+        index
+    }
+}
+
+pub(crate) fn encode(archive: &BenchArchive) -> Result<Vec<u8>> {
+    let mut interner = Interner::default();
+    let benches = encode_benches(&mut interner, &archive.benches);
+    let baselines = archive
+        .baselines
+        .iter()
+        .map(|baseline| CompactBaseline {
+            name: baseline.name.clone(),
+            pinned_at: baseline.pinned_at,
+            benches: encode_benches(&mut interner, &baseline.benches),
+        })
+        .collect();
+    let compact = CompactArchive {
+        version: archive.version,
+        reset_id: archive.reset_id,
+        title: archive.title.clone(),
+        strings: interner.strings,
+        benches,
+        baselines,
+    };
+
+    let packed = serde_cbor::ser::to_vec_packed(&compact).map_err(|e| ArchiveError::Encode {
+        reason: e.to_string(),
+    })?;
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(miniz_oxide::deflate::compress_to_vec(&packed, 9));
+    Ok(bytes)
+}
+
+fn encode_benches(interner: &mut Interner, benches: &[Bench]) -> Vec<CompactBench> {
+    benches
+        .iter()
+        .map(|bench| {
+            let mut keys: Vec<(&String, &String)> = bench.keys.iter().collect();
+            keys.sort();
+            let mut previous = 0;
+            CompactBench {
+                name: interner.intern(&bench.name),
+                keys: keys
+                    .into_iter()
+                    .map(|(key, value)| (interner.intern(key), interner.intern(value)))
+                    .collect(),
+                values: bench
+                    .measurements
+                    .iter()
+                    .map(|measurement| {
+                        let bits = measurement.value.to_bits();
+                        let delta = bits ^ previous;
+                        previous = bits;
+                        delta
+                    })
+                    .collect(),
+                measurements: bench
+                    .measurements
+                    .iter()
+                    .map(|measurement| CompactMeasurement {
+                        samples: measurement.samples.clone(),
+                        stats: measurement.stats.clone(),
+                        metadata: measurement.metadata.as_ref().map(|metadata| {
+                            let mut intern =
+                                |x: &Option<String>| x.as_ref().map(|x| interner.intern(x));
+                            CompactMetadata {
+                                commit: intern(&metadata.commit),
+                                branch: intern(&metadata.branch),
+                                commit_time: metadata.commit_time,
+                                run_time: metadata.run_time,
+                                toolchain: intern(&metadata.toolchain),
+                                runner: intern(&metadata.runner),
+                            }
+                        }),
+                        finding: measurement.finding.clone(),
+                    })
+                    .collect(),
+                missed_runs: bench.missed_runs,
+                retired: bench.retired,
+            }
+        })
+        .collect()
+}
+
+/// `bytes` must start with `MAGIC`
+pub(crate) fn decode(bytes: &[u8]) -> Result<BenchArchive> {
+    let packed = miniz_oxide::inflate::decompress_to_vec_with_limit(
+        &bytes[MAGIC.len()..],
+        MAX_DECOMPRESSED_BYTES,
+    )
+    .map_err(|e| match e.status {
+        TINFLStatus::HasMoreOutput => ArchiveError::corrupt(format!(
+            "Decompresses to more than {MAX_DECOMPRESSED_BYTES} bytes"
+        )),
+        _ => ArchiveError::corrupt(format!("Failed to decompress: {e}")),
+    })?;
+    let compact: CompactArchive = serde_cbor::from_slice(&packed).map_err(|e| {
+        // Check the version before the layout, as the layout may have changed in a newer version
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        match serde_cbor::from_slice::<Version>(&packed) {
+            Ok(Version { version }) if version > CURRENT_VERSION => {
+                ArchiveError::UnsupportedVersion { version }
+            }
+            _ => ArchiveError::corrupt(e),
+        }
+    })?;
+    if compact.version > CURRENT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion {
+            version: compact.version,
+        });
+    }
+
+    let strings = &compact.strings;
+    let baselines = compact
+        .baselines
+        .into_iter()
+        .map(|baseline| {
+            Ok(Baseline {
+                name: baseline.name,
+                pinned_at: baseline.pinned_at,
+                benches: decode_benches(strings, baseline.benches)?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(BenchArchive {
+        // Older layouts are upgraded as they are read
+        version: CURRENT_VERSION,
+        reset_id: compact.reset_id,
+        title: compact.title,
+        benches: decode_benches(strings, compact.benches)?,
+        baselines,
+    })
+}
+
+fn decode_benches(strings: &[String], compact: Vec<CompactBench>) -> Result<Vec<Bench>> {
+    let string = |index: u32| {
+        strings
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| ArchiveError::corrupt(format!("Missing string {index}")))
+    };
+    let optional_string = |index: Option<u32>| index.map(string).transpose();
+    let mut benches = vec![];
+    for bench in compact {
+        if bench.values.len() != bench.measurements.len() {
+            return Err(ArchiveError::corrupt(format!(
+                "Bench has {} values but {} measurements",
+                bench.values.len(),
+                bench.measurements.len()
+            )));
+        }
+        let mut previous = 0;
+        let mut measurements = vec![];
+        for (delta, measurement) in bench.values.into_iter().zip(bench.measurements) {
+            previous ^= delta;
+            measurements.push(BenchMeasurement {
+                value: f32::from_bits(previous),
+                samples: measurement.samples,
+                stats: measurement.stats,
+                metadata: match measurement.metadata {
+                    Some(metadata) => Some(RunMetadata {
+                        commit: optional_string(metadata.commit)?,
+                        branch: optional_string(metadata.branch)?,
+                        commit_time: metadata.commit_time,
+                        run_time: metadata.run_time,
+                        toolchain: optional_string(metadata.toolchain)?,
+                        runner: optional_string(metadata.runner)?,
+                    }),
+                    None => None,
+                },
+                finding: measurement.finding,
+            });
+        }
+        benches.push(Bench {
+            name: string(bench.name)?,
+            keys: bench
+                .keys
+                .into_iter()
+                .map(|(key, value)| Ok((string(key)?, string(value)?)))
+                .collect::<Result<_>>()?,
+            measurements,
+            missed_runs: bench.missed_runs,
+            retired: bench.retired,
+        });
+    }
+    Ok(benches)
+}
@@ -0,0 +1,113 @@
+//! Compares the latest measurements of matching series across two archives.
+//!
+//! Whether an increase is reported as a regression or an improvement depends on the `Direction` of the series.
+
+use crate::query::Query;
+use crate::{Bench, BenchArchive, BenchMeasurement, Direction};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Regressed,
+    Improved,
+    /// The change was within the threshold or the noise band
+    Unchanged,
+    /// The series only exists in the new archive
+    Added,
+    /// The series only exists in the old archive
+    Removed,
+}
+
+pub struct BenchComparison<'a> {
+    pub name: &'a str,
+    pub keys: &'a HashMap<String, String>,
+    pub old: Option<&'a BenchMeasurement>,
+    pub new: Option<&'a BenchMeasurement>,
+}
+
+impl<'a> BenchComparison<'a> {
+    /// The relative change from old to new, e.g. `0.1` is a 10% increase
+    pub fn change(&self) -> Option<f32> {
+        let old = self.old?.value;
+        let new = self.new?.value;
+        if old == 0.0 {
+            return None;
+        }
+        Some((new - old) / old)
+    }
+
+    /// The relative change that could be explained by noise alone.
+    /// Derived from the confidence intervals of the two measurements, None if either measurement has no samples.
+    pub fn noise(&self) -> Option<f32> {
+        let relative_margin = |measurement: &BenchMeasurement| {
+            let stats = measurement.stats.as_ref()?;
+            let (lower, upper) = stats.confidence_interval;
+            (stats.mean != 0.0).then(|| (upper - lower) / 2.0 / stats.mean.abs())
+        };
+        Some(relative_margin(self.old?)? + relative_margin(self.new?)?)
+    }
+
+    /// `threshold` is the smallest relative change that is reported, e.g. `0.05` ignores changes under 5%.
+    /// Changes must also exceed the noise band to be reported.
+    pub fn verdict(&self, threshold: f32) -> Verdict {
+        let change = match (self.old, self.new) {
+            (None, _) => return Verdict::Added,
+            (_, None) => return Verdict::Removed,
+            _ => self.change().unwrap_or(0.0),
+        };
+        let noise = self.noise().unwrap_or(0.0);
+        if change.abs() <= threshold.max(noise) {
+            Verdict::Unchanged
+        } else if Direction::of_keys(self.keys).is_worse(change) {
+            Verdict::Regressed
+        } else {
+            Verdict::Improved
+        }
+    }
+
+    /// The keys formatted as `key=value` pairs, sorted by key
+    pub fn keys_string(&self) -> String {
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort();
+        keys.iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Matches benches by name and keys and compares the latest measurement of each series selected by `query`.
+/// Series only present in one of the archives are included as `Verdict::Added` or `Verdict::Removed`.
+pub fn compare<'a>(
+    old: &'a BenchArchive,
+    new: &'a BenchArchive,
+    query: &Query,
+) -> Vec<BenchComparison<'a>> {
+    let latest = |archive: &'a BenchArchive, bench: &Bench| {
+        archive.find(bench).and_then(|x| x.measurements.last())
+    };
+
+    let mut comparisons: Vec<BenchComparison<'a>> = new
+        .query(query)
+        .into_iter()
+        .map(|series| BenchComparison {
+            name: &series.bench.name,
+            keys: &series.bench.keys,
+            old: latest(old, series.bench),
+            new: series.measurements.last(),
+        })
+        .collect();
+    comparisons.extend(
+        old.query(query)
+            .into_iter()
+            .filter(|series| new.find(series.bench).is_none())
+            .map(|series| BenchComparison {
+                name: &series.bench.name,
+                keys: &series.bench.keys,
+                old: series.measurements.last(),
+                new: None,
+            }),
+    );
+    comparisons.sort_by_cached_key(|x| (x.name.to_owned(), x.keys_string()));
+    comparisons
+}
@@ -0,0 +1,129 @@
+//! Imports the results that criterion stores in `target/criterion` so that existing history can seed an archive.
+//!
+//! criterion stores each bench in its own directory containing a directory per saved baseline, e.g.
+//! `target/criterion/mat4 mul vec4/new/{benchmark.json, estimates.json, sample.json}`.
+//! `new` is the most recent run and `base` the run before it, named baselines are saved with `--save-baseline`.
+
+use crate::error::{ArchiveError, Result};
+use crate::{Bench, BenchArchive, BenchMeasurement, RunMetadata};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+#[derive(Deserialize)]
+struct Benchmark {
+    /// e.g. `group/function/parameter`
+    full_id: String,
+}
+
+#[derive(Deserialize)]
+struct Estimates {
+    mean: Estimate,
+    /// Only present for linear sampling
+    slope: Option<Estimate>,
+}
+
+#[derive(Deserialize)]
+struct Estimate {
+    /// In nanoseconds
+    point_estimate: f64,
+}
+
+#[derive(Deserialize)]
+struct Sample {
+    iters: Vec<f64>,
+    /// The total time taken by each sample in nanoseconds
+    times: Vec<f64>,
+}
+
+impl BenchArchive {
+    /// Imports every bench under `dir` that has results for `baseline`, usually `new` for the latest run.
+    ///
+    /// Each bench is keyed with `machine` set to `machine_type` and `type` set to `walltime (ns)`, matching the series recorded by ussal-server.
+    /// The value is criterion's best estimate: the slope for linear sampling and otherwise the mean, matching what criterion reports.
+    /// The run time is taken from when criterion wrote the results.
+    pub fn from_criterion_dir(
+        title: String,
+        dir: &Path,
+        baseline: &str,
+        machine_type: &str,
+    ) -> Result<Self> {
+        let mut result_dirs = vec![];
+        find_result_dirs(dir, baseline, &mut result_dirs)?;
+        if result_dirs.is_empty() {
+            return Err(ArchiveError::NoCriterionResults {
+                dir: dir.to_owned(),
+                baseline: baseline.to_owned(),
+            });
+        }
+
+        let keys = HashMap::from([
+            ("machine".to_owned(), machine_type.to_owned()),
+            ("type".to_owned(), "walltime (ns)".to_owned()),
+        ]);
+        let mut benches = vec![];
+        for result_dir in result_dirs {
+            let (name, measurement) = load_result(&result_dir)?;
+            benches.push(Bench::new(name, keys.clone(), vec![measurement]));
+        }
+        benches.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(BenchArchive::new(title, benches))
+    }
+}
+
+fn find_result_dirs(dir: &Path, baseline: &str, found: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).map_err(|e| ArchiveError::io(dir, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| ArchiveError::io(dir, e))?.path();
+        if !path.is_dir() || path.file_name().is_some_and(|x| x == "report") {
+            continue;
+        }
+        if path.file_name().is_some_and(|x| x == baseline) && path.join("estimates.json").exists() {
+            found.push(path);
+        } else {
+            find_result_dirs(&path, baseline, found)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_result(dir: &Path) -> Result<(String, BenchMeasurement)> {
+    let benchmark: Benchmark = read_json(&dir.join("benchmark.json"))?;
+    let estimates: Estimates = read_json(&dir.join("estimates.json"))?;
+    let sample: Sample = read_json(&dir.join("sample.json"))?;
+    if sample.iters.len() != sample.times.len() {
+        return Err(ArchiveError::corrupt(format!(
+            "{:?} has {} iteration counts but {} times",
+            dir.join("sample.json"),
+            sample.iters.len(),
+            sample.times.len()
+        )));
+    }
+
+    let value = estimates.slope.unwrap_or(estimates.mean).point_estimate;
+    let samples = sample
+        .times
+        .iter()
+        .zip(&sample.iters)
+        .map(|(time, iters)| (time / iters) as f32)
+        .collect();
+    let run_time = std::fs::metadata(dir.join("estimates.json"))
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs() as i64);
+
+    let measurement =
+        BenchMeasurement::with_samples(value as f32, samples).with_metadata(RunMetadata {
+            run_time,
+            ..Default::default()
+        });
+    Ok((benchmark.full_id, measurement))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = std::fs::read(path).map_err(|e| ArchiveError::io(path, e))?;
+    serde_json::from_slice(&bytes)
+        .map_err(|e| ArchiveError::corrupt(format!("Failed to parse {path:?}: {e}")))
+}
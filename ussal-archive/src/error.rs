@@ -0,0 +1,67 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub type Result<T, E = ArchiveError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// Reading or writing a file failed
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The data could not be parsed as an archive
+    Corrupt { reason: String },
+    /// The archive was written by a newer version of ussal
+    UnsupportedVersion { version: u32 },
+    /// Two archives could not be combined, e.g. because they have different titles
+    Incompatible { reason: String },
+    /// The archive could not be encoded
+    Encode { reason: String },
+    /// A criterion target directory was read but contained no results for the baseline
+    NoCriterionResults { dir: PathBuf, baseline: String },
+}
+
+impl ArchiveError {
+    pub(crate) fn io(path: impl AsRef<Path>, error: std::io::Error) -> Self {
+        ArchiveError::Io {
+            path: path.as_ref().to_owned(),
+            error,
+        }
+    }
+
+    pub(crate) fn corrupt(reason: impl fmt::Display) -> Self {
+        ArchiveError::Corrupt {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Io { path, error } => write!(f, "Failed to access {path:?}: {error}"),
+            ArchiveError::Corrupt { reason } => write!(f, "The archive is corrupt: {reason}"),
+            ArchiveError::UnsupportedVersion { version } => write!(
+                f,
+                "The archive is version {version} but this version of ussal only supports up to version {}, upgrade ussal to read it",
+                crate::CURRENT_VERSION
+            ),
+            ArchiveError::Incompatible { reason } => write!(f, "{reason}"),
+            ArchiveError::Encode { reason } => write!(f, "Failed to encode the archive: {reason}"),
+            ArchiveError::NoCriterionResults { dir, baseline } => write!(
+                f,
+                "{dir:?} contains no criterion results for the baseline {baseline:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ArchiveError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
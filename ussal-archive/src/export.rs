@@ -0,0 +1,273 @@
+//! Plain text formats for working with archives in other tools such as analysis scripts and spreadsheets.
+//!
+//! # JSON
+//!
+//! A direct mapping of `BenchArchive`, nothing is lost:
+//! ```json
+//! {
+//!   "version": 4,
+//!   "reset_id": 0,
+//!   "title": "Ussal Example benches",
+//!   "benches": [
+//!     {
+//!       "name": "vec3 dot",
+//!       "keys": { "machine": "aarch64-linux-rpi4", "type": "walltime (ns)" },
+//!       "measurements": [
+//!         {
+//!           "value": 1.5,
+//!           "samples": [1.4, 1.5, 1.6] or null,
+//!           "stats": { "mean": 1.5, "median": 1.5, "std_dev": 0.1, "min": 1.4, "max": 1.6, "confidence_interval": [1.39, 1.61] } or null,
+//!           "metadata": { "commit": "4b825dc...", "branch": "main", "commit_time": 1700000000, "run_time": 1700000100, "toolchain": "rustc 1.76.0", "runner": "..." } or null,
+//!           "finding": { "kind": "Regression" or "Improvement", "change": 0.12, "baseline": 1.34, "score": 4.5 or null } or null
+//!         }
+//!       ],
+//!       "missed_runs": 0,
+//!       "retired": false
+//!     }
+//!   ],
+//!   "baselines": [
+//!     { "name": "v1.0", "pinned_at": 1700000200 or null, "benches": [ same as the benches above, with one measurement each ] }
+//!   ]
+//! }
+//! ```
+//! Any field of `metadata` may be null.
+//! JSON written by older versions of ussal is upgraded on import in the same way as CBOR archives.
+//!
+//! # CSV
+//!
+//! One row per measurement with the columns:
+//! * `name`
+//! * `key:$key` for every key used in the archive, empty when a series does not have the key
+//! * `value`
+//! * `samples` space separated, empty when there are no samples
+//! * `commit`, `branch`, `commit_time`, `run_time`, `toolchain`, `runner` empty when unknown
+//!
+//! Rows of the same series are in order.
+//! CSV is lossy: stats of measurements without samples, findings, retirement and baselines are not included.
+
+use crate::error::{ArchiveError, Result};
+use crate::{Bench, BenchArchive, BenchMeasurement, RunMetadata};
+use std::collections::{BTreeSet, HashMap};
+
+const METADATA_COLUMNS: [&str; 6] = [
+    "commit",
+    "branch",
+    "commit_time",
+    "run_time",
+    "toolchain",
+    "runner",
+];
+
+impl BenchArchive {
+    /// Objects are written with their keys in sorted order, so exporting the same archive always gives the same json
+    pub fn to_json(&self) -> Result<String> {
+        let encode = |e: serde_json::Error| ArchiveError::Encode {
+            reason: e.to_string(),
+        };
+        let value = serde_json::to_value(self).map_err(encode)?;
+        serde_json::to_string_pretty(&value).map_err(encode)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| ArchiveError::corrupt(format!("Failed to parse json: {e}")))?;
+        let value = serde_cbor::value::to_value(value).map_err(ArchiveError::corrupt)?;
+        Self::from_value(value)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let keys: BTreeSet<&str> = self
+            .benches
+            .iter()
+            .flat_map(|bench| bench.keys.keys().map(|key| key.as_str()))
+            .collect();
+
+        let mut header = vec!["name".to_owned()];
+        header.extend(keys.iter().map(|key| format!("key:{key}")));
+        header.extend(["value".to_owned(), "samples".to_owned()]);
+        header.extend(METADATA_COLUMNS.iter().map(|x| x.to_string()));
+        let mut out = csv_row(&header);
+
+        for bench in &self.benches {
+            for measurement in &bench.measurements {
+                let mut row = vec![bench.name.clone()];
+                row.extend(
+                    keys.iter()
+                        .map(|key| bench.keys.get(*key).cloned().unwrap_or_default()),
+                );
+                row.push(measurement.value.to_string());
+                row.push(
+                    measurement
+                        .samples
+                        .iter()
+                        .flatten()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+                let metadata = measurement.metadata.clone().unwrap_or_default();
+                row.extend([
+                    metadata.commit.unwrap_or_default(),
+                    metadata.branch.unwrap_or_default(),
+                    metadata
+                        .commit_time
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                    metadata.run_time.map(|x| x.to_string()).unwrap_or_default(),
+                    metadata.toolchain.unwrap_or_default(),
+                    metadata.runner.unwrap_or_default(),
+                ]);
+                out.push_str(&csv_row(&row));
+            }
+        }
+        out
+    }
+
+    /// CSV does not include the archive title so it must be provided.
+    pub fn from_csv(title: String, csv: &str) -> Result<Self> {
+        let mut rows = parse_csv(csv)?.into_iter();
+        let header = rows
+            .next()
+            .ok_or_else(|| ArchiveError::corrupt("CSV is empty"))?;
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|x| x == name)
+                .ok_or_else(|| ArchiveError::corrupt(format!("CSV is missing the {name:?} column")))
+        };
+        let name_column = column("name")?;
+        let value_column = column("value")?;
+        let samples_column = column("samples")?;
+        let metadata_columns = METADATA_COLUMNS
+            .iter()
+            .map(|name| column(name))
+            .collect::<Result<Vec<_>>>()?;
+        let key_columns: Vec<(usize, &str)> = header
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| Some((i, name.strip_prefix("key:")?)))
+            .collect();
+
+        let mut archive = BenchArchive::new(title, vec![]);
+        for (i, row) in rows.enumerate() {
+            let line = i + 2;
+            if row.len() != header.len() {
+                return Err(ArchiveError::corrupt(format!(
+                    "Row {line} has {} columns but the header has {}",
+                    row.len(),
+                    header.len()
+                )));
+            }
+            let keys: HashMap<String, String> = key_columns
+                .iter()
+                .filter(|(i, _)| !row[*i].is_empty())
+                .map(|(i, key)| (key.to_string(), row[*i].clone()))
+                .collect();
+            let parse_f32 = |text: &str| {
+                text.parse::<f32>().map_err(|_| {
+                    ArchiveError::corrupt(format!("Row {line} has an invalid number {text:?}"))
+                })
+            };
+            let parse_i64 = |text: &str| -> Result<Option<i64>> {
+                if text.is_empty() {
+                    return Ok(None);
+                }
+                text.parse().map(Some).map_err(|_| {
+                    ArchiveError::corrupt(format!("Row {line} has an invalid time {text:?}"))
+                })
+            };
+            let text = |i: usize| Some(row[metadata_columns[i]].clone()).filter(|x| !x.is_empty());
+
+            let value = parse_f32(&row[value_column])?;
+            let samples = row[samples_column]
+                .split_whitespace()
+                .map(parse_f32)
+                .collect::<Result<Vec<_>>>()?;
+            let mut measurement = if samples.is_empty() {
+                BenchMeasurement::new(value)
+            } else {
+                BenchMeasurement::with_samples(value, samples)
+            };
+            let metadata = RunMetadata {
+                commit: text(0),
+                branch: text(1),
+                commit_time: parse_i64(&row[metadata_columns[2]])?,
+                run_time: parse_i64(&row[metadata_columns[3]])?,
+                toolchain: text(4),
+                runner: text(5),
+            };
+            if metadata != RunMetadata::default() {
+                measurement = measurement.with_metadata(metadata);
+            }
+
+            let name = row[name_column].clone();
+            match archive
+                .benches
+                .iter_mut()
+                .find(|bench| bench.name == name && bench.keys == keys)
+            {
+                Some(bench) => bench.measurements.push(measurement),
+                None => archive
+                    .benches
+                    .push(Bench::new(name, keys, vec![measurement])),
+            }
+        }
+        Ok(archive)
+    }
+}
+
+fn csv_row(fields: &[String]) -> String {
+    let mut row = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push('\n');
+    row
+}
+
+/// Parses RFC 4180 CSV, fields may be quoted with `"` and contain escaped quotes as `""`.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+        } else {
+            match c {
+                '"' => quoted = true,
+                ',' => row.push(std::mem::take(&mut field)),
+                '\r' if chars.peek() == Some(&'\n') => {}
+                '\n' => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                c => field.push(c),
+            }
+        }
+    }
+    if quoted {
+        return Err(ArchiveError::corrupt("CSV ends inside of a quoted field"));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
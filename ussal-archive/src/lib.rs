@@ -1,9 +1,29 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::all, rust_2018_idioms)]
 
-use anyhow::{anyhow, Result};
+use baseline::Baseline;
+use error::Result;
+use regression::Finding;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Write;
+use ussal_stats::Summary;
+
+pub mod baseline;
+mod compact;
+pub mod compare;
+mod criterion;
+mod error;
+mod export;
+mod migrations;
+pub mod query;
+pub mod regression;
+pub mod rename;
+pub mod report;
+pub mod retention;
+
+pub use error::ArchiveError;
+pub use migrations::CURRENT_VERSION;
 
 #[derive(Serialize, Deserialize)]
 pub struct BenchArchive {
@@ -11,9 +31,10 @@ pub struct BenchArchive {
     reset_id: u32,
     pub title: String,
     pub benches: Vec<Bench>,
+    pub baselines: Vec<Baseline>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Bench {
     pub name: String,
     // A set of keys used when combining measurements and for filtering
@@ -25,32 +46,200 @@ pub struct Bench {
     // Users will also be able to overwrite these defaults and add their own
     pub keys: HashMap<String, String>,
     pub measurements: Vec<BenchMeasurement>,
+    /// The number of consecutive runs inserted into the history that did not include this series
+    pub missed_runs: u32,
+    /// The series is no longer being run, set by `BenchArchive::retire`
+    pub retired: bool,
+}
+
+impl Bench {
+    pub fn new(
+        name: String,
+        keys: HashMap<String, String>,
+        measurements: Vec<BenchMeasurement>,
+    ) -> Self {
+        Bench {
+            name,
+            keys,
+            measurements,
+            missed_runs: 0,
+            retired: false,
+        }
+    }
+}
+
+/// Whether a smaller or a larger value is better for a series
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    LowerIsBetter,
+    HigherIsBetter,
+}
+
+impl Direction {
+    /// Derived from the `type` key: throughput and rates such as `throughput (bytes/s)` are higher is better.
+    /// Everything else, including series without a `type` key, is lower is better e.g. `walltime (ns)` or `instructions`.
+    pub fn of_keys(keys: &HashMap<String, String>) -> Self {
+        let ty = keys.get("type").map(|x| x.as_str()).unwrap_or("");
+        let (name, unit) = match ty.split_once(" (") {
+            Some((name, unit)) => (name, unit.trim_end_matches(')')),
+            None => (ty, ""),
+        };
+        if name.contains("throughput") || unit.ends_with("/s") {
+            Direction::HigherIsBetter
+        } else {
+            Direction::LowerIsBetter
+        }
+    }
+
+    /// Whether a relative change of the series, e.g. `0.1` for a 10% increase, makes it worse
+    pub fn is_worse(self, change: f32) -> bool {
+        match self {
+            Direction::LowerIsBetter => change > 0.0,
+            Direction::HigherIsBetter => change < 0.0,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BenchMeasurement {
-    // TODO: build hash and/or datetime?
     pub value: f32,
+    /// The raw samples that `value` was derived from.
+    /// Older archives and some measurement methods do not provide samples.
+    pub samples: Option<Vec<f32>>,
+    /// Summary of `samples`, kept separately so that it is still available if the samples are discarded to save space.
+    pub stats: Option<BenchStats>,
+    /// Describes where the measurement came from, not available for measurements in older archives.
+    pub metadata: Option<RunMetadata>,
+    /// Set when the measurement was a significant change from the history before it
+    pub finding: Option<Finding>,
+}
+
+/// Each field is optional as it may not be possible to determine it in every environment.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RunMetadata {
+    /// The git commit SHA of the code that was benched
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+    /// Seconds since the unix epoch
+    pub commit_time: Option<i64>,
+    /// Seconds since the unix epoch
+    pub run_time: Option<i64>,
+    /// e.g. `rustc 1.76.0 (07dca489a 2024-02-04)`
+    pub toolchain: Option<String>,
+    /// Describes the hardware and OS of the runner that took the measurement
+    pub runner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BenchStats {
+    pub mean: f32,
+    pub median: f32,
+    pub std_dev: f32,
+    pub min: f32,
+    pub max: f32,
+    /// The 95% confidence interval of the mean, as (lower bound, upper bound)
+    pub confidence_interval: (f32, f32),
+}
+
+impl BenchMeasurement {
+    /// A measurement with no information about its noise
+    pub fn new(value: f32) -> Self {
+        BenchMeasurement {
+            value,
+            samples: None,
+            stats: None,
+            metadata: None,
+            finding: None,
+        }
+    }
+
+    pub fn with_samples(value: f32, samples: Vec<f32>) -> Self {
+        BenchMeasurement {
+            value,
+            stats: BenchStats::from_samples(&samples),
+            samples: Some(samples),
+            metadata: None,
+            finding: None,
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: RunMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Measurements without metadata can not be told apart, so are never considered the same run.
+    fn is_same_run(&self, other: &BenchMeasurement) -> bool {
+        self.metadata.is_some() && self.metadata == other.metadata && self.value == other.value
+    }
+}
+
+/// Orders measurements by commit time and then run time.
+/// Stable, so measurements without metadata keep their relative order at the start of the series.
+pub(crate) fn sort_by_time(measurements: &mut [BenchMeasurement]) {
+    measurements.sort_by_key(|x| {
+        x.metadata
+            .as_ref()
+            .map(|metadata| (metadata.commit_time, metadata.run_time))
+    });
+}
+
+impl BenchStats {
+    /// Returns None if there are no samples.
+    /// The statistics are computed by the same `Summary` that bench binaries report, so the two always agree.
+    pub fn from_samples(samples: &[f32]) -> Option<Self> {
+        let samples: Vec<f64> = samples.iter().map(|x| *x as f64).collect();
+        let summary = Summary::from_samples(&samples)?;
+        // Uses the normal approximation, which is reasonable for the sample counts produced by bench harnesses.
+        let margin = 1.96 * summary.std_dev / (samples.len() as f64).sqrt();
+
+        Some(BenchStats {
+            mean: summary.mean as f32,
+            median: summary.median as f32,
+            std_dev: summary.std_dev as f32,
+            min: summary.min as f32,
+            max: summary.max as f32,
+            confidence_interval: (
+                (summary.mean - margin) as f32,
+                (summary.mean + margin) as f32,
+            ),
+        })
+    }
 }
 
 impl BenchArchive {
     pub fn new(name: String, benches: Vec<Bench>) -> Self {
         BenchArchive {
-            version: 0,
+            version: CURRENT_VERSION,
             reset_id: 0,
             title: name,
             benches,
+            baselines: vec![],
         }
     }
 
+    /// Archives can only be merged when their reset ids match, so per run archives should be created with the configured reset id.
+    pub fn with_reset_id(mut self, reset_id: u32) -> Self {
+        self.reset_id = reset_id;
+        self
+    }
+
     pub fn reset_if_mismatch(&mut self, reset_id: u32) {
         if reset_id != self.reset_id {
             self.benches.clear();
+            self.baselines.clear();
             self.reset_id = reset_id;
         }
     }
 
+    /// Appends a new run to the history.
+    /// Series that are not included in the run have their `missed_runs` incremented.
     pub fn insert(&mut self, new_archive: BenchArchive) {
+        if !new_archive.benches.is_empty() {
+            for bench in &mut self.benches {
+                bench.missed_runs += 1;
+            }
+        }
         for new_bench in new_archive.benches {
             let mut found = false;
             for bench in &mut self.benches {
@@ -58,6 +247,8 @@ impl BenchArchive {
                     bench
                         .measurements
                         .extend(new_bench.measurements.iter().cloned());
+                    bench.missed_runs = 0;
+                    bench.retired = false;
                     found = true;
                     break;
                 }
@@ -68,20 +259,135 @@ impl BenchArchive {
         }
     }
 
+    /// Marks series that were not included in the last `after_runs` runs as retired.
+    /// Retired series are kept, so they return to normal if they are run again.
+    pub fn retire(&mut self, after_runs: u32) {
+        for bench in &mut self.benches {
+            bench.retired = bench.missed_runs >= after_runs;
+        }
+    }
+
+    /// Combines the series in `other` into `self`, e.g. to combine the results of parallel CI workflows into one history.
+    ///
+    /// Measurements from different machines are kept in separate series by their `machine` key.
+    /// Within a series, measurements are ordered by commit time and then run time so that runs of the same commit line up regardless of the order they were merged in.
+    /// Measurements that are already present are skipped, so merging the same archive twice has no effect.
+    /// Baselines are kept from `self` when both archives have a baseline of the same name.
+    ///
+    /// Fails without modifying `self` if the archives have different titles or reset ids.
+    pub fn merge(&mut self, other: BenchArchive) -> Result<()> {
+        if self.title != other.title {
+            return Err(ArchiveError::Incompatible {
+                reason: format!(
+                    "Cannot merge archive titled {:?} into archive titled {:?}",
+                    other.title, self.title
+                ),
+            });
+        }
+        if self.reset_id != other.reset_id {
+            return Err(ArchiveError::Incompatible {
+                reason: format!(
+                    "Cannot merge archive {:?} with reset id {} into archive with reset id {}",
+                    other.title, other.reset_id, self.reset_id
+                ),
+            });
+        }
+
+        for new_bench in other.benches {
+            let bench = match self
+                .benches
+                .iter()
+                .position(|bench| bench.name == new_bench.name && bench.keys == new_bench.keys)
+            {
+                Some(i) => &mut self.benches[i],
+                None => {
+                    self.benches.push(Bench {
+                        measurements: vec![],
+                        ..new_bench
+                    });
+                    self.benches.last_mut().unwrap()
+                }
+            };
+            for measurement in new_bench.measurements {
+                if !bench
+                    .measurements
+                    .iter()
+                    .any(|x| x.is_same_run(&measurement))
+                {
+                    bench.measurements.push(measurement);
+                }
+            }
+            sort_by_time(&mut bench.measurements);
+        }
+        for baseline in other.baselines {
+            if self.baseline(&baseline.name).is_none() {
+                self.baselines.push(baseline);
+            }
+        }
+        Ok(())
+    }
+
     pub fn load(path: &str) -> Result<Self> {
-        // TODO: retain backwards compatibility by performing format upgrades here
-        serde_cbor::from_slice(
-            &std::fs::read(path)
-                .map_err(|e| anyhow!(e).context(format!("Failed to read {path:?} from disk")))?,
-        )
-        .map_err(|e| anyhow!(e).context(format!("Failed to parse {path:?} as json")))
+        let bytes = std::fs::read(path).map_err(|e| ArchiveError::io(path, e))?;
+        Self::load_from_cbor(&bytes)
     }
 
-    pub fn load_from_cbor(bytes: &[u8]) -> Self {
-        serde_cbor::from_slice(bytes).unwrap()
+    /// Reads archives in either the plain or compact encoding.
+    /// Archives written by older versions of ussal are upgraded to the current format
+    pub fn load_from_cbor(bytes: &[u8]) -> Result<Self> {
+        if Self::is_compact(bytes) {
+            return compact::decode(bytes);
+        }
+        let value: serde_cbor::Value = serde_cbor::from_slice(bytes)
+            .map_err(|e| ArchiveError::corrupt(format!("Failed to parse as cbor: {e}")))?;
+        Self::from_value(value)
+    }
+
+    /// Upgrades and parses an archive in its generic form, shared by all of the archive encodings
+    fn from_value(mut value: serde_cbor::Value) -> Result<Self> {
+        migrations::migrate(&mut value)?;
+        serde_cbor::value::from_value(value).map_err(ArchiveError::corrupt)
     }
 
-    pub fn save(&self, name: &str) {
-        std::fs::write(name, serde_cbor::to_vec(self).unwrap()).unwrap();
+    /// Saves the archive as plain cbor, which other tools can read without knowing about ussal.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let bytes = serde_cbor::to_vec(self).map_err(|e| ArchiveError::Encode {
+            reason: e.to_string(),
+        })?;
+        write_atomic(path, &bytes)
+    }
+
+    /// Saves the archive in a compressed encoding that is a fraction of the size of plain cbor,
+    /// intended for histories that are fetched by the web viewer.
+    pub fn save_compact(&self, path: &str) -> Result<()> {
+        write_atomic(path, &self.to_compact()?)
+    }
+
+    pub fn to_compact(&self) -> Result<Vec<u8>> {
+        compact::encode(self)
+    }
+
+    /// Whether `bytes` were written by `save_compact`, so that tools modifying an archive can keep its encoding
+    pub fn is_compact(bytes: &[u8]) -> bool {
+        bytes.starts_with(compact::MAGIC)
+    }
+}
+
+/// The bytes are written to a temporary file which then replaces `path`,
+/// so `path` is never left partially written if the process is interrupted.
+fn write_atomic(path: &str, bytes: &[u8]) -> Result<()> {
+    let temp_path = format!("{path}.{}.tmp", std::process::id());
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()
+    };
+    if let Err(err) = write() {
+        std::fs::remove_file(&temp_path).ok();
+        return Err(ArchiveError::io(&temp_path, err));
     }
+    std::fs::rename(&temp_path, path).map_err(|e| {
+        std::fs::remove_file(&temp_path).ok();
+        ArchiveError::io(path, e)
+    })
 }
@@ -0,0 +1,129 @@
+//! Upgrades archives written by older versions of ussal to the current format.
+//!
+//! Archives are persisted across releases in gh-pages, so every format change must:
+//! 1. bump `CURRENT_VERSION`
+//! 2. add a migration from the previous version to `MIGRATIONS`
+//! 3. add a golden file of the new version to `ussal-archive/tests/golden`
+//! 4. update `compact.rs`, which only stores the current version, to still read the previous layout
+
+use crate::error::{ArchiveError, Result};
+use serde_cbor::Value;
+
+/// The version written by `BenchArchive::save`
+pub const CURRENT_VERSION: u32 = 4;
+
+/// `MIGRATIONS[i]` upgrades an archive from version `i` to version `i + 1`
+const MIGRATIONS: [fn(&mut Value) -> Result<()>; CURRENT_VERSION as usize] =
+    [v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+/// Upgrades the archive in place to `CURRENT_VERSION`
+pub fn migrate(archive: &mut Value) -> Result<()> {
+    let version = version(archive)?;
+    if version > CURRENT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion { version });
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(archive).map_err(|e| match e {
+            ArchiveError::Corrupt { reason } => {
+                ArchiveError::corrupt(format!("Failed to upgrade from version {from}: {reason}"))
+            }
+            e => e,
+        })?;
+        *field(archive, "version")? = Value::Integer(from as i128 + 1);
+    }
+    Ok(())
+}
+
+fn version(archive: &mut Value) -> Result<u32> {
+    match field(archive, "version")? {
+        Value::Integer(version) => u32::try_from(*version).map_err(|_| {
+            ArchiveError::corrupt(format!("The archive has an invalid version {version}"))
+        }),
+        value => Err(ArchiveError::corrupt(format!(
+            "The archive has an invalid version {value:?}"
+        ))),
+    }
+}
+
+fn field<'a>(value: &'a mut Value, name: &str) -> Result<&'a mut Value> {
+    match value {
+        Value::Map(map) => map
+            .get_mut(&Value::Text(name.to_owned()))
+            .ok_or_else(|| ArchiveError::corrupt(format!("Missing field {name:?}"))),
+        _ => Err(ArchiveError::corrupt(format!(
+            "Expected a map containing {name:?} but was {value:?}"
+        ))),
+    }
+}
+
+fn array<'a>(value: &'a mut Value, name: &str) -> Result<&'a mut Vec<Value>> {
+    match field(value, name)? {
+        Value::Array(array) => Ok(array),
+        value => Err(ArchiveError::corrupt(format!(
+            "Expected {name:?} to be an array but was {value:?}"
+        ))),
+    }
+}
+
+/// Measurements gained samples, summary statistics and run metadata.
+fn v0_to_v1(archive: &mut Value) -> Result<()> {
+    add_measurement_fields(archive, &["samples", "stats", "metadata"])
+}
+
+/// Measurements gained regression detection findings.
+fn v1_to_v2(archive: &mut Value) -> Result<()> {
+    add_measurement_fields(archive, &["finding"])
+}
+
+/// Benches gained tracking of whether they are still being run.
+fn v2_to_v3(archive: &mut Value) -> Result<()> {
+    for bench in array(archive, "benches")? {
+        match bench {
+            Value::Map(map) => {
+                map.insert(Value::Text("missed_runs".to_owned()), Value::Integer(0));
+                map.insert(Value::Text("retired".to_owned()), Value::Bool(false));
+            }
+            value => {
+                return Err(ArchiveError::corrupt(format!(
+                    "Expected a bench but was {value:?}"
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Archives gained named baselines.
+fn v3_to_v4(archive: &mut Value) -> Result<()> {
+    match archive {
+        Value::Map(map) => {
+            map.insert(Value::Text("baselines".to_owned()), Value::Array(vec![]));
+            Ok(())
+        }
+        value => Err(ArchiveError::corrupt(format!(
+            "Expected an archive but was {value:?}"
+        ))),
+    }
+}
+
+/// Adds the optional `fields` to every measurement with no value
+fn add_measurement_fields(archive: &mut Value, fields: &[&str]) -> Result<()> {
+    for bench in array(archive, "benches")? {
+        for measurement in array(bench, "measurements")? {
+            match measurement {
+                Value::Map(map) => {
+                    for name in fields {
+                        map.insert(Value::Text(name.to_string()), Value::Null);
+                    }
+                }
+                value => {
+                    return Err(ArchiveError::corrupt(format!(
+                        "Expected a measurement but was {value:?}"
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Selects series from an archive and combines them, shared by the viewer, comparisons and reports.
+//!
+//! A `Query` selects series by name and keys, e.g. every `vec3 *` bench on one machine, and slices their measurements by time or commit.
+//! `aggregate` then combines the selected series across a key, e.g. the median across every machine.
+
+use crate::{Bench, BenchArchive, BenchMeasurement};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyPredicate {
+    /// The key is set to this value
+    Equals(String),
+    /// The key is set to one of these values
+    OneOf(Vec<String>),
+    /// The key is not set, or is set to a value other than these
+    NoneOf(Vec<String>),
+    Present,
+    Absent,
+}
+
+impl KeyPredicate {
+    fn matches(&self, value: Option<&String>) -> bool {
+        match self {
+            KeyPredicate::Equals(expected) => value == Some(expected),
+            KeyPredicate::OneOf(values) => value.is_some_and(|x| values.contains(x)),
+            KeyPredicate::NoneOf(values) => !value.is_some_and(|x| values.contains(x)),
+            KeyPredicate::Present => value.is_some(),
+            KeyPredicate::Absent => value.is_none(),
+        }
+    }
+}
+
+/// Matches every series until narrowed down with the `with_*` methods.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    name: Option<NameMatch>,
+    keys: Vec<(String, KeyPredicate)>,
+    /// Bounds on the run time, inclusive
+    run_time: (Option<i64>, Option<i64>),
+    /// Bounds on the commits, inclusive
+    commits: (Option<String>, Option<String>),
+    exclude_retired: bool,
+}
+
+#[derive(Clone, Debug)]
+enum NameMatch {
+    Exact(String),
+    Glob(String),
+}
+
+impl Query {
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(NameMatch::Exact(name.to_owned()));
+        self
+    }
+
+    /// `*` matches any number of characters and `?` matches a single character, e.g. `vec3 *`
+    pub fn with_name_glob(mut self, glob: &str) -> Self {
+        self.name = Some(NameMatch::Glob(glob.to_owned()));
+        self
+    }
+
+    /// Every predicate must match, so multiple predicates on the same key narrow the query further
+    pub fn with_key(mut self, key: &str, predicate: KeyPredicate) -> Self {
+        self.keys.push((key.to_owned(), predicate));
+        self
+    }
+
+    /// Only keeps measurements run between `since` and `until` inclusive, in seconds since the unix epoch
+    pub fn with_time_range(mut self, since: Option<i64>, until: Option<i64>) -> Self {
+        self.run_time = (since, until);
+        self
+    }
+
+    /// Only keeps measurements of commits between `from` and `to` inclusive.
+    /// Commits are ordered by commit time, so measurements of other commits made in between are also kept.
+    pub fn with_commit_range(mut self, from: Option<&str>, to: Option<&str>) -> Self {
+        self.commits = (from.map(|x| x.to_owned()), to.map(|x| x.to_owned()));
+        self
+    }
+
+    pub fn without_retired(mut self) -> Self {
+        self.exclude_retired = true;
+        self
+    }
+
+    /// Whether the series is selected, ignoring the time and commit ranges
+    pub fn matches(&self, bench: &Bench) -> bool {
+        let name_matches = match &self.name {
+            None => true,
+            Some(NameMatch::Exact(name)) => &bench.name == name,
+            Some(NameMatch::Glob(glob)) => glob_matches(glob, &bench.name),
+        };
+        name_matches
+            && !(self.exclude_retired && bench.retired)
+            && self
+                .keys
+                .iter()
+                .all(|(key, predicate)| predicate.matches(bench.keys.get(key)))
+    }
+
+    fn slices(&self) -> bool {
+        self.run_time != (None, None) || self.commits != (None, None)
+    }
+}
+
+/// A selected series, sliced down to the measurements within the query's ranges
+pub struct Series<'a> {
+    pub bench: &'a Bench,
+    /// The index of the first measurement in `bench.measurements`
+    pub offset: usize,
+    pub measurements: &'a [BenchMeasurement],
+}
+
+impl BenchArchive {
+    /// Series are sliced from their first measurement at or after the start of the ranges to their last measurement at or before the end.
+    /// Measurements without a run time or commit time can not be placed in a range, so are excluded from the ends of a slice.
+    /// Series with no measurements within the ranges are left out.
+    pub fn query(&self, query: &Query) -> Vec<Series<'_>> {
+        // Resolve commits to commit times, so that series that did not run at exactly those commits are still sliced.
+        // A commit that is not in the archive matches nothing.
+        let commit_time = |commit: &Option<String>| match commit {
+            None => Some(None),
+            Some(commit) => self
+                .benches
+                .iter()
+                .flat_map(|bench| &bench.measurements)
+                .filter_map(|x| x.metadata.as_ref())
+                .find(|x| x.commit.as_ref() == Some(commit))
+                .and_then(|x| x.commit_time)
+                .map(Some),
+        };
+        let (Some(from_commit), Some(to_commit)) =
+            (commit_time(&query.commits.0), commit_time(&query.commits.1))
+        else {
+            return vec![];
+        };
+        let in_range = |measurement: &BenchMeasurement| {
+            let metadata = measurement.metadata.as_ref();
+            let within = |value: Option<i64>, (since, until): (Option<i64>, Option<i64>)| {
+                (since.is_none() && until.is_none())
+                    || value.is_some_and(|value| {
+                        since.unwrap_or(i64::MIN) <= value && value <= until.unwrap_or(i64::MAX)
+                    })
+            };
+            within(metadata.and_then(|x| x.run_time), query.run_time)
+                && within(
+                    metadata.and_then(|x| x.commit_time),
+                    (from_commit, to_commit),
+                )
+        };
+
+        self.benches
+            .iter()
+            .filter(|bench| query.matches(bench))
+            .filter_map(|bench| {
+                let start = bench.measurements.iter().position(in_range);
+                let end = bench.measurements.iter().rposition(in_range);
+                match (start, end) {
+                    (Some(start), Some(end)) => Some(Series {
+                        bench,
+                        offset: start,
+                        measurements: &bench.measurements[start..=end],
+                    }),
+                    _ if !query.slices() => Some(Series {
+                        bench,
+                        offset: 0,
+                        measurements: &bench.measurements,
+                    }),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Every key used in the archive along with every value it is set to
+    pub fn key_values(&self) -> BTreeMap<&str, BTreeSet<&str>> {
+        let mut keys: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for bench in &self.benches {
+            for (key, value) in &bench.keys {
+                keys.entry(key).or_default().insert(value);
+            }
+        }
+        keys
+    }
+
+    /// The series in this archive with the same name and keys as `other`
+    pub fn find(&self, other: &Bench) -> Option<&Bench> {
+        self.benches
+            .iter()
+            .find(|bench| bench.name == other.name && bench.keys == other.keys)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    Median,
+    Mean,
+    Min,
+    Max,
+}
+
+/// Series that only differ by the aggregated key, combined into one
+pub struct AggregatedSeries {
+    pub name: String,
+    /// The keys shared by the combined series, which excludes the aggregated key
+    pub keys: HashMap<String, String>,
+    /// How many series were combined
+    pub series: usize,
+    pub points: Vec<AggregatedPoint>,
+}
+
+pub struct AggregatedPoint {
+    /// None for measurements without a commit, which are combined by their position in each series instead
+    pub commit: Option<String>,
+    pub value: f32,
+    /// How many measurements were combined, less than the number of series when some series did not run the commit
+    pub count: usize,
+}
+
+/// The measurements that are combined into one `AggregatedPoint`
+struct Point<'a> {
+    commit: Option<&'a str>,
+    /// The position of the measurement among the measurements without a commit, 0 for measurements with a commit
+    position: usize,
+    values: Vec<f32>,
+}
+
+/// Combines series that only differ by `key`, e.g. the median across every `machine`.
+/// Measurements of the same commit are combined, in the order that the commits first appear.
+pub fn aggregate(
+    series: &[Series<'_>],
+    key: &str,
+    aggregation: Aggregation,
+) -> Vec<AggregatedSeries> {
+    let mut groups: Vec<(AggregatedSeries, Vec<Point<'_>>)> = vec![];
+    for series in series {
+        let mut keys = series.bench.keys.clone();
+        keys.remove(key);
+        let (group, points) = match groups
+            .iter_mut()
+            .position(|(group, _)| group.name == series.bench.name && group.keys == keys)
+        {
+            Some(i) => &mut groups[i],
+            None => {
+                groups.push((
+                    AggregatedSeries {
+                        name: series.bench.name.clone(),
+                        keys,
+                        series: 0,
+                        points: vec![],
+                    },
+                    vec![],
+                ));
+                groups.last_mut().unwrap()
+            }
+        };
+        group.series += 1;
+
+        let mut without_commit = 0;
+        for measurement in series.measurements {
+            let commit = measurement
+                .metadata
+                .as_ref()
+                .and_then(|x| x.commit.as_deref());
+            let position = if commit.is_some() {
+                0
+            } else {
+                without_commit += 1;
+                without_commit
+            };
+            match points
+                .iter_mut()
+                .find(|x| x.commit == commit && x.position == position)
+            {
+                Some(point) => point.values.push(measurement.value),
+                None => points.push(Point {
+                    commit,
+                    position,
+                    values: vec![measurement.value],
+                }),
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(mut group, points)| {
+            group.points = points
+                .into_iter()
+                .map(|point| AggregatedPoint {
+                    commit: point.commit.map(|x| x.to_owned()),
+                    value: combine(&point.values, aggregation),
+                    count: point.values.len(),
+                })
+                .collect();
+            group
+        })
+        .collect()
+}
+
+fn combine(values: &[f32], aggregation: Aggregation) -> f32 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let len = sorted.len();
+    match aggregation {
+        Aggregation::Median if len % 2 == 1 => sorted[len / 2],
+        Aggregation::Median => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+        Aggregation::Mean => sorted.iter().sum::<f32>() / len as f32,
+        Aggregation::Min => sorted[0],
+        Aggregation::Max => sorted[len - 1],
+    }
+}
+
+/// `*` matches any number of characters and `?` matches a single character
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // The position to resume from when the last `*` needs to match one more character
+    let mut backtrack = None;
+    let (mut g, mut t) = (0, 0);
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    g = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|x| *x == '*')
+}
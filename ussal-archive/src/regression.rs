@@ -0,0 +1,166 @@
+//! Detects regressions and improvements by comparing a new run against the recent history of each series.
+//!
+//! The baseline is the median of the last few measurements in the history.
+//! The new measurement is flagged when it differs from the baseline by more than a threshold and by more than the
+//! variation that the series has recently shown, measured as a robust z-score using the median absolute deviation.
+
+use crate::{BenchArchive, Direction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+pub struct DetectionConfig {
+    /// How many of the most recent measurements form the baseline
+    pub baseline_runs: usize,
+    /// Baselines with fewer measurements than this are not considered reliable enough to report against
+    pub min_baseline_runs: usize,
+    /// The smallest relative change that is reported, e.g. `0.05` ignores changes under 5%
+    pub threshold: f32,
+    /// How many (robust) standard deviations away from the baseline a measurement must be to be reported
+    pub min_score: f32,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            baseline_runs: 10,
+            min_baseline_runs: 3,
+            threshold: 0.05,
+            min_score: 3.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FindingKind {
+    Regression,
+    Improvement,
+}
+
+/// A significant change of a measurement relative to the history before it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub kind: FindingKind,
+    /// The relative change from the baseline, e.g. `0.1` is a 10% increase
+    pub change: f32,
+    /// The median of the measurements the new measurement was compared against
+    pub baseline: f32,
+    /// How many (robust) standard deviations the measurement is from the baseline.
+    /// None when the baseline had no variation at all.
+    pub score: Option<f32>,
+}
+
+/// A finding along with the series it was found in.
+/// Displays as e.g. `mat4 mul vec4 got 12.0% slower on aarch64-linux-rpi4 (walltime (ns))`
+pub struct SeriesFinding {
+    pub bench_name: String,
+    pub keys: HashMap<String, String>,
+    pub finding: Finding,
+}
+
+impl fmt::Display for SeriesFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.finding.kind {
+            FindingKind::Regression => "slower",
+            FindingKind::Improvement => "faster",
+        };
+        write!(
+            f,
+            "{} got {:.1}% {direction}",
+            self.bench_name,
+            self.finding.change.abs() * 100.0
+        )?;
+        if let Some(machine) = self.keys.get("machine") {
+            write!(f, " on {machine}")?;
+        }
+        if let Some(ty) = self.keys.get("type") {
+            write!(f, " ({ty})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares `value` against the `history` that came before it, `direction` decides whether the change is a regression.
+/// Returns None if the change is not significant or there is not enough history to tell.
+pub fn detect(
+    history: &[f32],
+    value: f32,
+    direction: Direction,
+    config: &DetectionConfig,
+) -> Option<Finding> {
+    let recent = recent(history, config)?;
+    let baseline = median(recent.to_vec());
+    if baseline == 0.0 {
+        return None;
+    }
+    let change = (value - baseline) / baseline;
+    // 1.4826 scales the median absolute deviation to match the standard deviation of normally distributed data.
+    let deviation = 1.4826 * median(recent.iter().map(|x| (x - baseline).abs()).collect());
+    let score = (deviation != 0.0).then(|| (value - baseline).abs() / deviation);
+
+    if change.abs() <= config.threshold || score.is_some_and(|x| x < config.min_score) {
+        return None;
+    }
+    Some(Finding {
+        kind: if direction.is_worse(change) {
+            FindingKind::Regression
+        } else {
+            FindingKind::Improvement
+        },
+        change,
+        baseline,
+        score,
+    })
+}
+
+/// The value that new measurements are compared against.
+/// Returns None if there is not enough history to form a reliable baseline.
+pub fn baseline(history: &[f32], config: &DetectionConfig) -> Option<f32> {
+    recent(history, config).map(|recent| median(recent.to_vec()))
+}
+
+fn recent<'a>(history: &'a [f32], config: &DetectionConfig) -> Option<&'a [f32]> {
+    let start = history.len().saturating_sub(config.baseline_runs);
+    let recent = &history[start..];
+    (recent.len() >= config.min_baseline_runs.max(1)).then_some(recent)
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let len = values.len();
+    if len % 2 == 1 {
+        values[len / 2]
+    } else {
+        (values[len / 2 - 1] + values[len / 2]) / 2.0
+    }
+}
+
+impl BenchArchive {
+    /// Compares the latest measurement of every series in the `new` run against the history in `self`.
+    /// Findings are recorded on the measurements in `new` and also returned.
+    pub fn detect_changes(
+        &self,
+        new: &mut BenchArchive,
+        config: &DetectionConfig,
+    ) -> Vec<SeriesFinding> {
+        let mut findings = vec![];
+        for new_bench in &mut new.benches {
+            let history: Vec<f32> = self
+                .find(new_bench)
+                .map(|bench| bench.measurements.iter().map(|x| x.value).collect())
+                .unwrap_or_default();
+            if let Some(measurement) = new_bench.measurements.last_mut() {
+                let direction = Direction::of_keys(&new_bench.keys);
+                measurement.finding = detect(&history, measurement.value, direction, config);
+                if let Some(finding) = &measurement.finding {
+                    findings.push(SeriesFinding {
+                        bench_name: new_bench.name.clone(),
+                        keys: new_bench.keys.clone(),
+                        finding: finding.clone(),
+                    });
+                }
+            }
+        }
+        findings
+    }
+}
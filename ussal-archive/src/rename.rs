@@ -0,0 +1,83 @@
+//! Carries the history of a series over to a new identity when a bench is renamed or its keys change.
+
+use crate::{sort_by_time, Bench, BenchArchive};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// e.g. `{"from_name": "vec3 dot", "to_name": "vec3 dot product"}`
+/// or `{"from_name": "mat4 mul", "to_keys": {"machine": "aarch64-linux-rpi4"}}` for a series that was recorded before it had a `machine` key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rename {
+    /// Matches series with this name
+    pub from_name: String,
+    /// Matches series that have all of these keys, other keys are ignored
+    #[serde(default)]
+    pub from_keys: HashMap<String, String>,
+    /// The new name, by default the name is unchanged
+    #[serde(default)]
+    pub to_name: Option<String>,
+    /// Keys to add or replace.
+    /// A key not in `from_keys` is only added to series that do not have that key yet, existing values are never silently replaced.
+    #[serde(default)]
+    pub to_keys: HashMap<String, String>,
+}
+
+impl Rename {
+    fn matches(&self, bench: &Bench) -> bool {
+        bench.name == self.from_name
+            && self
+                .from_keys
+                .iter()
+                .all(|(key, value)| bench.keys.get(key) == Some(value))
+            && self
+                .to_keys
+                .keys()
+                .all(|key| self.from_keys.contains_key(key) || !bench.keys.contains_key(key))
+    }
+}
+
+impl BenchArchive {
+    /// Applies each rename in order and returns how many series were renamed.
+    /// When a series with the new identity already exists, the renamed series' measurements are combined into it.
+    /// Baselines are renamed too, so they continue to line up with the history.
+    pub fn rename(&mut self, renames: &[Rename]) -> usize {
+        for baseline in &mut self.baselines {
+            rename_benches(&mut baseline.benches, renames);
+        }
+        rename_benches(&mut self.benches, renames)
+    }
+}
+
+fn rename_benches(benches: &mut Vec<Bench>, renames: &[Rename]) -> usize {
+    let mut renamed = 0;
+    for rename in renames {
+        let (matched, rest): (Vec<Bench>, Vec<Bench>) = std::mem::take(benches)
+            .into_iter()
+            .partition(|bench| rename.matches(bench));
+        *benches = rest;
+
+        for mut bench in matched {
+            renamed += 1;
+            if let Some(name) = &rename.to_name {
+                bench.name = name.clone();
+            }
+            bench.keys.extend(rename.to_keys.clone());
+
+            match benches
+                .iter_mut()
+                .find(|x| x.name == bench.name && x.keys == bench.keys)
+            {
+                Some(existing) => {
+                    // The renamed series is usually the older history, so it goes first for measurements without a time.
+                    bench.measurements.append(&mut existing.measurements);
+                    existing.measurements = bench.measurements;
+                    sort_by_time(&mut existing.measurements);
+                    existing.missed_runs = existing.missed_runs.min(bench.missed_runs);
+                    existing.retired &= bench.retired;
+                }
+                None => benches.push(bench),
+            }
+        }
+    }
+    renamed
+}
@@ -0,0 +1,121 @@
+//! Renders a markdown summary of a run compared against a history, suitable for posting as a PR comment.
+
+use crate::query::{KeyPredicate, Query};
+use crate::regression::{self, DetectionConfig, FindingKind};
+use crate::{BenchArchive, Direction};
+
+struct Row<'a> {
+    name: &'a str,
+    ty: &'a str,
+    baseline: Option<f32>,
+    value: f32,
+    marker: &'static str,
+}
+
+/// Compares the latest measurement of every series in `run` against the recent measurements of the same series in `history`.
+/// The results are rendered as a table per machine type.
+pub fn markdown(
+    history: &BenchArchive,
+    run: &BenchArchive,
+    config: &DetectionConfig,
+    viewer_url: Option<&str>,
+) -> String {
+    // Benches without a machine are listed last
+    let mut machines: Vec<Option<&str>> = run
+        .key_values()
+        .remove("machine")
+        .unwrap_or_default()
+        .into_iter()
+        .map(Some)
+        .collect();
+    machines.push(None);
+
+    let mut tables = vec![];
+    for machine in machines {
+        let predicate = match machine {
+            Some(machine) => KeyPredicate::Equals(machine.to_owned()),
+            None => KeyPredicate::Absent,
+        };
+        let mut rows = vec![];
+        for series in run.query(&Query::default().with_key("machine", predicate)) {
+            let bench = series.bench;
+            let Some(measurement) = series.measurements.last() else {
+                continue;
+            };
+            let values: Vec<f32> = history
+                .find(bench)
+                .map(|bench| bench.measurements.iter().map(|x| x.value).collect())
+                .unwrap_or_default();
+            let baseline = regression::baseline(&values, config);
+            let direction = Direction::of_keys(&bench.keys);
+            let marker = match regression::detect(&values, measurement.value, direction, config) {
+                Some(finding) => match finding.kind {
+                    FindingKind::Regression => "🔴",
+                    FindingKind::Improvement => "🟢",
+                },
+                None if baseline.is_none() => "🆕",
+                None => "",
+            };
+            rows.push(Row {
+                name: &bench.name,
+                ty: bench.keys.get("type").map(|x| x.as_str()).unwrap_or(""),
+                baseline,
+                value: measurement.value,
+                marker,
+            });
+        }
+        if !rows.is_empty() {
+            tables.push((machine.unwrap_or(""), rows));
+        }
+    }
+
+    let mut out = format!("# {}\n\n", run.title);
+    if let Some(url) = viewer_url {
+        out.push_str(&format!("[View the full history]({url})\n\n"));
+    }
+    if tables.is_empty() {
+        out.push_str("No benches were run.\n");
+    }
+    for (machine, mut rows) in tables {
+        rows.sort_by_key(|row| (row.name, row.ty));
+        out.push_str(&format!("## {machine}\n\n"));
+        out.push_str("| Bench | Type | Baseline | New | Change | |\n");
+        out.push_str("|---|---|---:|---:|---:|---|\n");
+        for row in rows {
+            let (baseline, change) = match row.baseline {
+                Some(baseline) if baseline != 0.0 => (
+                    format_value(baseline),
+                    format!("{:+.1}%", (row.value - baseline) / baseline * 100.0),
+                ),
+                Some(baseline) => (format_value(baseline), String::new()),
+                None => (String::new(), String::new()),
+            };
+            out.push_str(&format!(
+                "| {} | {} | {baseline} | {} | {change} | {} |\n",
+                escape(row.name),
+                escape(row.ty),
+                format_value(row.value),
+                row.marker,
+            ));
+        }
+        out.push('\n');
+    }
+    out.push_str(&format!(
+        "🔴 significantly slower, 🟢 significantly faster, 🆕 not enough history to compare against.\nThe baseline is the median of the last {} runs.\n",
+        config.baseline_runs
+    ));
+    out
+}
+
+fn format_value(value: f32) -> String {
+    if value.abs() >= 100.0 {
+        format!("{value:.0}")
+    } else {
+        format!("{value:.2}")
+    }
+}
+
+/// Prevents `|` from ending a table cell early
+fn escape(text: &str) -> String {
+    text.replace('|', "\\|")
+}
@@ -0,0 +1,137 @@
+//! Bounds the size of a history by downsampling old measurements and dropping series that are no longer run.
+
+use crate::{BenchArchive, BenchMeasurement, BenchStats};
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetentionPolicy {
+    /// The most recent measurements of each series that are kept as is
+    pub full_resolution_runs: usize,
+    /// Measurements older than the full resolution runs are combined into one measurement per period
+    pub aggregate: AggregatePeriod,
+    /// Series that have not been run for this many days are removed
+    #[serde(default)]
+    pub drop_after_days: Option<u32>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            full_resolution_runs: 100,
+            aggregate: AggregatePeriod::Weekly,
+            drop_after_days: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregatePeriod {
+    Daily,
+    /// Weeks start on monday
+    Weekly,
+}
+
+impl AggregatePeriod {
+    fn bucket(self, time: i64) -> i64 {
+        let day = time.div_euclid(SECONDS_PER_DAY);
+        match self {
+            AggregatePeriod::Daily => day,
+            // The unix epoch was a thursday
+            AggregatePeriod::Weekly => (day + 3).div_euclid(7),
+        }
+    }
+}
+
+impl BenchArchive {
+    /// Applies `policy` to every series, `now` is the current time in seconds since the unix epoch.
+    ///
+    /// An aggregated measurement has the median value of the measurements it replaces and the metadata of the latest of them.
+    /// Its samples are discarded and its stats instead summarize the values of the measurements it replaces.
+    /// Periods containing a single measurement keep that measurement minus its samples.
+    /// A period is only aggregated once all of its measurements are older than the full resolution runs,
+    /// so compacting after every run aggregates each period exactly once rather than repeatedly folding new measurements into an aggregate.
+    /// Measurements without a run time can not be placed in a period, so they are kept but their samples are discarded.
+    pub fn compact(&mut self, policy: &RetentionPolicy, now: i64) {
+        if let Some(days) = policy.drop_after_days {
+            let cutoff = now - days as i64 * SECONDS_PER_DAY;
+            self.benches.retain(|bench| {
+                match bench.measurements.iter().filter_map(run_time).max() {
+                    Some(last_run) => last_run >= cutoff,
+                    None => true,
+                }
+            });
+        }
+
+        for bench in &mut self.benches {
+            let mut old_len = bench
+                .measurements
+                .len()
+                .saturating_sub(policy.full_resolution_runs);
+            let first_recent_period = bench.measurements[old_len..]
+                .iter()
+                .find_map(run_time)
+                .map(|time| policy.aggregate.bucket(time));
+            if let Some(first_recent_period) = first_recent_period {
+                while old_len > 0
+                    && run_time(&bench.measurements[old_len - 1])
+                        .is_some_and(|time| policy.aggregate.bucket(time) >= first_recent_period)
+                {
+                    old_len -= 1;
+                }
+            }
+            let recent = bench.measurements.split_off(old_len);
+
+            let mut compacted: Vec<BenchMeasurement> = vec![];
+            let mut group: Vec<BenchMeasurement> = vec![];
+            for measurement in bench.measurements.drain(..) {
+                let Some(time) = run_time(&measurement) else {
+                    compacted.extend(aggregate(std::mem::take(&mut group)));
+                    compacted.push(BenchMeasurement {
+                        samples: None,
+                        ..measurement
+                    });
+                    continue;
+                };
+                let same_period = group.last().and_then(run_time).is_some_and(|last| {
+                    policy.aggregate.bucket(last) == policy.aggregate.bucket(time)
+                });
+                if !same_period {
+                    compacted.extend(aggregate(std::mem::take(&mut group)));
+                }
+                group.push(measurement);
+            }
+            compacted.extend(aggregate(group));
+            compacted.extend(recent);
+            bench.measurements = compacted;
+        }
+    }
+}
+
+fn run_time(measurement: &BenchMeasurement) -> Option<i64> {
+    measurement.metadata.as_ref()?.run_time
+}
+
+fn aggregate(mut group: Vec<BenchMeasurement>) -> Option<BenchMeasurement> {
+    if group.len() == 1 {
+        // Keep the stats of the original samples, they are more informative than the stats of a single value.
+        return group.pop().map(|measurement| BenchMeasurement {
+            samples: None,
+            ..measurement
+        });
+    }
+
+    let values: Vec<f32> = group.iter().map(|x| x.value).collect();
+    let stats = BenchStats::from_samples(&values)?;
+    let finding = group.iter().find_map(|x| x.finding.clone());
+    let latest = group.into_iter().last()?;
+    Some(BenchMeasurement {
+        value: stats.median,
+        samples: None,
+        stats: Some(stats),
+        metadata: latest.metadata,
+        finding,
+    })
+}
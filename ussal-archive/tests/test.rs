@@ -0,0 +1,1300 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use ussal_archive::compare::{compare, Verdict};
+use ussal_archive::query::{aggregate, Aggregation, KeyPredicate, Query, Series};
+use ussal_archive::regression::{detect, DetectionConfig, Finding, FindingKind};
+use ussal_archive::rename::Rename;
+use ussal_archive::report;
+use ussal_archive::retention::{AggregatePeriod, RetentionPolicy};
+use ussal_archive::{
+    ArchiveError, Bench, BenchArchive, BenchMeasurement, BenchStats, Direction, RunMetadata,
+    CURRENT_VERSION,
+};
+use ussal_stats::Summary;
+
+#[test]
+fn test_load_archive_without_samples() {
+    // The layout of archives written before measurements could contain samples
+    #[derive(Serialize)]
+    struct OldArchive {
+        version: u32,
+        reset_id: u32,
+        title: String,
+        benches: Vec<OldBench>,
+    }
+    #[derive(Serialize)]
+    struct OldBench {
+        name: String,
+        keys: HashMap<String, String>,
+        measurements: Vec<OldMeasurement>,
+    }
+    #[derive(Serialize)]
+    struct OldMeasurement {
+        value: f32,
+    }
+
+    let old = OldArchive {
+        version: 0,
+        reset_id: 0,
+        title: "old".to_owned(),
+        benches: vec![OldBench {
+            name: "bench".to_owned(),
+            keys: HashMap::new(),
+            measurements: vec![OldMeasurement { value: 1.5 }],
+        }],
+    };
+    let archive = BenchArchive::load_from_cbor(&serde_cbor::to_vec(&old).unwrap()).unwrap();
+
+    let measurement = &archive.benches[0].measurements[0];
+    assert_eq!(measurement.value, 1.5);
+    assert!(measurement.samples.is_none());
+    assert!(measurement.stats.is_none());
+}
+
+#[test]
+fn test_measurement_stats() {
+    // The statistics themselves are tested in ussal-bench, this only checks that they are carried over
+    let measurement = BenchMeasurement::with_samples(10.0, vec![9.0, 12.0, 10.0]);
+    let stats = measurement.stats.unwrap();
+    let summary = Summary::from_samples(&[9.0, 12.0, 10.0]).unwrap();
+    assert_eq!(stats.median, summary.median as f32);
+    assert_eq!(stats.std_dev, summary.std_dev as f32);
+    assert_eq!((stats.min, stats.max), (9.0, 12.0));
+    assert!(stats.confidence_interval.0 < stats.mean && stats.confidence_interval.1 > stats.mean);
+
+    assert_eq!(BenchStats::from_samples(&[]), None);
+}
+
+fn key(bench: &Bench, key: &str) -> String {
+    bench.keys.get(key).unwrap().clone()
+}
+
+#[test]
+fn test_golden_v0() {
+    let archive = BenchArchive::load("tests/golden/v0.cbor").unwrap();
+    assert_eq!(archive.title, "golden");
+    assert_eq!(archive.benches.len(), 2);
+
+    let walltime = &archive.benches[0];
+    assert_eq!(walltime.name, "vec3 dot");
+    assert_eq!(key(walltime, "machine"), "x86");
+    assert_eq!(key(walltime, "type"), "walltime (ns)");
+    let values: Vec<f32> = walltime.measurements.iter().map(|x| x.value).collect();
+    assert_eq!(values, vec![1.25, 1.5]);
+    for measurement in &walltime.measurements {
+        assert!(measurement.samples.is_none());
+        assert!(measurement.stats.is_none());
+        assert!(measurement.metadata.is_none());
+    }
+
+    let instructions = &archive.benches[1];
+    assert_eq!(key(instructions, "type"), "instructions");
+    assert_eq!(instructions.measurements[0].value, 12.0);
+}
+
+#[test]
+fn test_golden_v1() {
+    let archive = BenchArchive::load("tests/golden/v1.cbor").unwrap();
+    assert_eq!(archive.title, "golden");
+    assert_eq!(archive.benches.len(), 2);
+
+    let walltime = &archive.benches[0];
+    assert_eq!(key(walltime, "type"), "walltime (ns)");
+    assert_eq!(walltime.measurements[0].value, 1.25);
+    assert!(walltime.measurements[0].samples.is_none());
+    assert!(walltime.measurements[0].metadata.is_none());
+
+    let measurement = &walltime.measurements[1];
+    assert_eq!(measurement.value, 1.5);
+    assert_eq!(measurement.samples, Some(vec![1.0, 1.5, 2.0]));
+    assert_eq!(measurement.stats.as_ref().unwrap().median, 1.5);
+    let metadata = measurement.metadata.as_ref().unwrap();
+    assert_eq!(
+        metadata.commit.as_deref(),
+        Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904")
+    );
+    assert_eq!(metadata.branch.as_deref(), Some("main"));
+    assert_eq!(metadata.commit_time, Some(1700000000));
+    assert_eq!(metadata.run_time, Some(1700000100));
+
+    let instructions = &archive.benches[1];
+    assert_eq!(key(instructions, "type"), "instructions");
+    assert_eq!(
+        instructions.measurements[0]
+            .metadata
+            .as_ref()
+            .unwrap()
+            .runner,
+        metadata.runner
+    );
+}
+
+#[test]
+fn test_golden_v2() {
+    let archive = BenchArchive::load("tests/golden/v2.cbor").unwrap();
+    assert_eq!(archive.benches.len(), 2);
+    assert!(archive.benches[0].measurements[1].finding.is_none());
+    assert_eq!(
+        archive.benches[1].measurements[0].finding,
+        Some(Finding {
+            kind: FindingKind::Regression,
+            change: 0.2,
+            baseline: 10.0,
+            score: Some(4.5),
+        })
+    );
+}
+
+#[test]
+fn test_golden_v1_has_no_findings() {
+    let archive = BenchArchive::load("tests/golden/v1.cbor").unwrap();
+    for bench in &archive.benches {
+        for measurement in &bench.measurements {
+            assert!(measurement.finding.is_none());
+        }
+    }
+}
+
+#[test]
+fn test_golden_v3() {
+    let archive = BenchArchive::load("tests/golden/v3.cbor").unwrap();
+    assert_eq!(archive.benches.len(), 2);
+    assert_eq!(archive.benches[0].missed_runs, 0);
+    assert!(!archive.benches[0].retired);
+    assert_eq!(archive.benches[1].missed_runs, 12);
+    assert!(archive.benches[1].retired);
+}
+
+#[test]
+fn test_golden_v3_compact() {
+    let plain = BenchArchive::load("tests/golden/v3.cbor").unwrap();
+    let compact = BenchArchive::load("tests/golden/v3_compact.cbor").unwrap();
+    assert_eq!(compact.to_json().unwrap(), plain.to_json().unwrap());
+}
+
+#[test]
+fn test_golden_v4() {
+    let archive = BenchArchive::load("tests/golden/v4.cbor").unwrap();
+    assert_eq!(archive.baselines.len(), 1);
+    let baseline = &archive.baselines[0];
+    assert_eq!(baseline.name, "v1.0");
+    assert_eq!(baseline.pinned_at, Some(1700000200));
+    // The retired series was not pinned
+    assert_eq!(baseline.benches.len(), 1);
+    assert_eq!(baseline.benches[0].name, archive.benches[0].name);
+    assert_eq!(
+        baseline.benches[0].measurements[0].value,
+        archive.benches[0].measurements.last().unwrap().value
+    );
+}
+
+#[test]
+fn test_golden_v3_has_no_baselines() {
+    for version in 0..=3 {
+        let archive = BenchArchive::load(&format!("tests/golden/v{version}.cbor")).unwrap();
+        assert!(archive.baselines.is_empty());
+    }
+}
+
+#[test]
+fn test_golden_v2_is_not_retired() {
+    let archive = BenchArchive::load("tests/golden/v2.cbor").unwrap();
+    for bench in &archive.benches {
+        assert_eq!(bench.missed_runs, 0);
+        assert!(!bench.retired);
+    }
+}
+
+#[test]
+fn test_golden_files_cover_every_version() {
+    for version in 0..=CURRENT_VERSION {
+        let path = format!("tests/golden/v{version}.cbor");
+        assert!(
+            std::path::Path::new(&path).exists(),
+            "{path} is missing, add a golden file when bumping the archive version"
+        );
+    }
+}
+
+#[test]
+fn test_load_newer_version() {
+    #[derive(Serialize)]
+    struct FutureArchive {
+        version: u32,
+    }
+
+    let path = std::env::temp_dir().join("ussal_archive_future_version.cbor");
+    let future = FutureArchive {
+        version: CURRENT_VERSION + 1,
+    };
+    std::fs::write(&path, serde_cbor::to_vec(&future).unwrap()).unwrap();
+
+    let err = BenchArchive::load(path.to_str().unwrap()).err().unwrap();
+    assert!(
+        matches!(err, ArchiveError::UnsupportedVersion { version } if version == CURRENT_VERSION + 1),
+        "unexpected error: {err:?}"
+    );
+    assert!(format!("{err}").contains("upgrade ussal"), "{err}");
+}
+
+#[test]
+fn test_load_errors() {
+    let err = BenchArchive::load("tests/golden/missing.cbor")
+        .err()
+        .unwrap();
+    assert!(
+        matches!(&err, ArchiveError::Io { error, .. } if error.kind() == std::io::ErrorKind::NotFound),
+        "unexpected error: {err:?}"
+    );
+
+    let err = BenchArchive::load_from_cbor(b"not an archive")
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, ArchiveError::Corrupt { .. }),
+        "unexpected error: {err:?}"
+    );
+
+    // Valid cbor but not an archive
+    let err = BenchArchive::load_from_cbor(&serde_cbor::to_vec(&vec![1, 2, 3]).unwrap())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, ArchiveError::Corrupt { .. }),
+        "unexpected error: {err:?}"
+    );
+
+    // Truncated, as if the write of the archive was interrupted
+    let bytes = std::fs::read("tests/golden/v3.cbor").unwrap();
+    let err = BenchArchive::load_from_cbor(&bytes[..bytes.len() / 2])
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, ArchiveError::Corrupt { .. }),
+        "unexpected error: {err:?}"
+    );
+}
+
+#[test]
+fn test_save_replaces_archive() {
+    let dir = std::env::temp_dir().join("ussal_archive_save");
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir(&dir).unwrap();
+    let path = dir.join("bench_history.cbor");
+    let path = path.to_str().unwrap();
+
+    run("x86", 1, 1.0).save(path).unwrap();
+    let mut history = BenchArchive::load(path).unwrap();
+    history.insert(run("x86", 2, 2.0));
+    history.save(path).unwrap();
+
+    let saved = BenchArchive::load(path).unwrap();
+    assert_eq!(saved.benches[0].measurements.len(), 2);
+    // The temporary file was renamed over the archive
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let err = history
+        .save(dir.join("missing/bench_history.cbor").to_str().unwrap())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, ArchiveError::Io { .. }),
+        "unexpected error: {err:?}"
+    );
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+fn archive(benches: Vec<(&str, &str, BenchMeasurement)>) -> BenchArchive {
+    BenchArchive::new(
+        "compare".to_owned(),
+        benches
+            .into_iter()
+            .map(|(name, machine, measurement)| {
+                Bench::new(
+                    name.to_owned(),
+                    HashMap::from([("machine".to_owned(), machine.to_owned())]),
+                    vec![measurement],
+                )
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn test_compare() {
+    let old = archive(vec![
+        ("faster", "x86", BenchMeasurement::new(100.0)),
+        ("slower", "x86", BenchMeasurement::new(100.0)),
+        ("slower", "arm", BenchMeasurement::new(100.0)),
+        (
+            "noisy",
+            "x86",
+            BenchMeasurement::with_samples(100.0, vec![50.0, 100.0, 150.0]),
+        ),
+        ("removed", "x86", BenchMeasurement::new(100.0)),
+    ]);
+    let new = archive(vec![
+        ("faster", "x86", BenchMeasurement::new(80.0)),
+        ("slower", "x86", BenchMeasurement::new(120.0)),
+        ("slower", "arm", BenchMeasurement::new(102.0)),
+        (
+            "noisy",
+            "x86",
+            BenchMeasurement::with_samples(120.0, vec![70.0, 120.0, 170.0]),
+        ),
+        ("added", "x86", BenchMeasurement::new(100.0)),
+    ]);
+
+    let verdicts: Vec<(String, String, Verdict)> = compare(&old, &new, &Query::default())
+        .iter()
+        .map(|x| (x.name.to_owned(), x.keys_string(), x.verdict(0.05)))
+        .collect();
+    assert_eq!(
+        verdicts,
+        vec![
+            ("added".to_owned(), "machine=x86".to_owned(), Verdict::Added),
+            (
+                "faster".to_owned(),
+                "machine=x86".to_owned(),
+                Verdict::Improved
+            ),
+            (
+                "noisy".to_owned(),
+                "machine=x86".to_owned(),
+                Verdict::Unchanged
+            ),
+            (
+                "removed".to_owned(),
+                "machine=x86".to_owned(),
+                Verdict::Removed
+            ),
+            (
+                "slower".to_owned(),
+                "machine=arm".to_owned(),
+                Verdict::Unchanged
+            ),
+            (
+                "slower".to_owned(),
+                "machine=x86".to_owned(),
+                Verdict::Regressed
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_detect() {
+    let config = DetectionConfig::default();
+    let history = [100.0, 102.0, 98.0, 101.0, 99.0];
+
+    // Within the threshold
+    assert_eq!(
+        detect(&history, 103.0, Direction::LowerIsBetter, &config),
+        None
+    );
+    // Not enough history to tell
+    assert_eq!(
+        detect(&history[..2], 150.0, Direction::LowerIsBetter, &config),
+        None
+    );
+
+    let regression = detect(&history, 112.0, Direction::LowerIsBetter, &config).unwrap();
+    assert_eq!(regression.kind, FindingKind::Regression);
+    assert_eq!(regression.baseline, 100.0);
+    assert!((regression.change - 0.12).abs() < 0.0001);
+
+    let improvement = detect(&history, 80.0, Direction::LowerIsBetter, &config).unwrap();
+    assert_eq!(improvement.kind, FindingKind::Improvement);
+
+    // Larger than the threshold but within the usual variation of the series
+    let noisy = [100.0, 130.0, 70.0, 120.0, 80.0];
+    assert_eq!(
+        detect(&noisy, 110.0, Direction::LowerIsBetter, &config),
+        None
+    );
+}
+
+#[test]
+fn test_direction() {
+    let direction =
+        |ty: &str| Direction::of_keys(&HashMap::from([("type".to_owned(), ty.to_owned())]));
+    assert_eq!(direction("walltime (ns)"), Direction::LowerIsBetter);
+    assert_eq!(direction("instructions"), Direction::LowerIsBetter);
+    assert_eq!(direction("throughput"), Direction::HigherIsBetter);
+    assert_eq!(direction("throughput (bytes)"), Direction::HigherIsBetter);
+    assert_eq!(direction("decoded (frames/s)"), Direction::HigherIsBetter);
+    assert_eq!(
+        Direction::of_keys(&HashMap::new()),
+        Direction::LowerIsBetter
+    );
+}
+
+#[test]
+fn test_detect_throughput() {
+    let config = DetectionConfig::default();
+    let history = [100.0, 102.0, 98.0, 101.0, 99.0];
+
+    let improvement = detect(&history, 112.0, Direction::HigherIsBetter, &config).unwrap();
+    assert_eq!(improvement.kind, FindingKind::Improvement);
+    assert!((improvement.change - 0.12).abs() < 0.0001);
+
+    let regression = detect(&history, 80.0, Direction::HigherIsBetter, &config).unwrap();
+    assert_eq!(regression.kind, FindingKind::Regression);
+}
+
+#[test]
+fn test_compare_throughput() {
+    let throughput = |value: f32| {
+        BenchArchive::new(
+            "throughput".to_owned(),
+            vec![Bench::new(
+                "decode".to_owned(),
+                HashMap::from([("type".to_owned(), "throughput (bytes/s)".to_owned())]),
+                vec![BenchMeasurement::new(value)],
+            )],
+        )
+    };
+    let verdict = |old: f32, new: f32| {
+        let (old, new) = (throughput(old), throughput(new));
+        let verdicts: Vec<Verdict> = compare(&old, &new, &Query::default())
+            .iter()
+            .map(|x| x.verdict(0.05))
+            .collect();
+        verdicts
+    };
+    assert_eq!(verdict(100.0, 120.0), vec![Verdict::Improved]);
+    assert_eq!(verdict(100.0, 80.0), vec![Verdict::Regressed]);
+    assert_eq!(verdict(100.0, 102.0), vec![Verdict::Unchanged]);
+}
+
+#[test]
+fn test_detect_changes() {
+    let mut history = archive(vec![("bench", "x86", BenchMeasurement::new(100.0))]);
+    for _ in 0..4 {
+        history.insert(archive(vec![(
+            "bench",
+            "x86",
+            BenchMeasurement::new(100.0),
+        )]));
+    }
+    let mut new = archive(vec![
+        ("bench", "x86", BenchMeasurement::new(112.0)),
+        ("new bench", "x86", BenchMeasurement::new(112.0)),
+    ]);
+
+    let findings = history.detect_changes(&mut new, &DetectionConfig::default());
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].to_string(), "bench got 12.0% slower on x86");
+    assert!(new.benches[0].measurements[0].finding.is_some());
+    assert!(new.benches[1].measurements[0].finding.is_none());
+}
+
+#[test]
+fn test_markdown_report() {
+    let mut history = archive(vec![
+        ("slower", "x86", BenchMeasurement::new(100.0)),
+        ("same", "arm", BenchMeasurement::new(10.0)),
+    ]);
+    for _ in 0..4 {
+        history.insert(archive(vec![
+            ("slower", "x86", BenchMeasurement::new(100.0)),
+            ("same", "arm", BenchMeasurement::new(10.0)),
+        ]));
+    }
+    let run = archive(vec![
+        ("slower", "x86", BenchMeasurement::new(120.0)),
+        ("same", "arm", BenchMeasurement::new(10.1)),
+        ("new", "x86", BenchMeasurement::new(5.0)),
+    ]);
+
+    let markdown = report::markdown(
+        &history,
+        &run,
+        &DetectionConfig::default(),
+        Some("https://example.com/bench"),
+    );
+    assert_eq!(
+        markdown,
+        "# compare
+
+[View the full history](https://example.com/bench)
+
+## arm
+
+| Bench | Type | Baseline | New | Change | |
+|---|---|---:|---:|---:|---|
+| same |  | 10.00 | 10.10 | +1.0% |  |
+
+## x86
+
+| Bench | Type | Baseline | New | Change | |
+|---|---|---:|---:|---:|---|
+| new |  |  | 5.00 |  | 🆕 |
+| slower |  | 100 | 120 | +20.0% | 🔴 |
+
+🔴 significantly slower, 🟢 significantly faster, 🆕 not enough history to compare against.
+The baseline is the median of the last 10 runs.
+"
+    );
+}
+
+fn run(machine: &str, commit_time: i64, value: f32) -> BenchArchive {
+    let metadata = RunMetadata {
+        commit: Some(format!("commit{commit_time}")),
+        commit_time: Some(commit_time),
+        run_time: Some(commit_time + 10),
+        ..Default::default()
+    };
+    archive(vec![(
+        "bench",
+        machine,
+        BenchMeasurement::new(value).with_metadata(metadata),
+    )])
+}
+
+#[test]
+fn test_merge() {
+    let mut merged = run("x86", 2, 2.0);
+    merged.merge(run("arm", 1, 10.0)).unwrap();
+    merged.merge(run("x86", 1, 1.0)).unwrap();
+    merged.merge(run("arm", 2, 20.0)).unwrap();
+    // Merging the same run twice has no effect
+    merged.merge(run("arm", 2, 20.0)).unwrap();
+
+    assert_eq!(merged.benches.len(), 2);
+    for (machine, expected) in [("x86", vec![1.0, 2.0]), ("arm", vec![10.0, 20.0])] {
+        let bench = merged
+            .benches
+            .iter()
+            .find(|x| key(x, "machine") == machine)
+            .unwrap();
+        let values: Vec<f32> = bench.measurements.iter().map(|x| x.value).collect();
+        assert_eq!(values, expected);
+        let commits: Vec<Option<String>> = bench
+            .measurements
+            .iter()
+            .map(|x| x.metadata.as_ref().unwrap().commit.clone())
+            .collect();
+        assert_eq!(
+            commits,
+            vec![Some("commit1".to_owned()), Some("commit2".to_owned())]
+        );
+    }
+}
+
+#[test]
+fn test_merge_conflicts() {
+    let mut merged = run("x86", 1, 1.0);
+
+    let mut other_title = run("x86", 2, 2.0);
+    other_title.title = "other".to_owned();
+    let err = merged.merge(other_title).unwrap_err();
+    assert!(matches!(err, ArchiveError::Incompatible { .. }), "{err}");
+    assert!(format!("{err}").contains("titled"), "{err}");
+
+    let err = merged
+        .merge(run("x86", 2, 2.0).with_reset_id(1))
+        .unwrap_err();
+    assert!(format!("{err}").contains("reset id"), "{err}");
+
+    // Nothing was merged
+    assert_eq!(merged.benches[0].measurements.len(), 1);
+}
+
+fn measurement_at(value: f32, run_time: i64) -> BenchMeasurement {
+    BenchMeasurement::with_samples(value, vec![value; 3]).with_metadata(RunMetadata {
+        run_time: Some(run_time),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_compact() {
+    const DAY: i64 = 24 * 60 * 60;
+    // 1970-01-05 was a monday
+    let monday = 4 * DAY;
+    let mut archive = archive(vec![("kept", "x86", BenchMeasurement::new(1.0))]);
+    archive.benches[0].measurements = vec![
+        BenchMeasurement::new(1.0),
+        measurement_at(1.0, monday),
+        measurement_at(3.0, monday + DAY),
+        measurement_at(2.0, monday + 6 * DAY),
+        measurement_at(10.0, monday + 7 * DAY),
+        measurement_at(20.0, monday + 8 * DAY),
+        measurement_at(30.0, monday + 9 * DAY),
+    ];
+    archive.insert(BenchArchive::new(
+        "compare".to_owned(),
+        vec![Bench::new(
+            "stale".to_owned(),
+            HashMap::new(),
+            vec![measurement_at(1.0, 0)],
+        )],
+    ));
+
+    let policy = RetentionPolicy {
+        full_resolution_runs: 2,
+        aggregate: AggregatePeriod::Weekly,
+        drop_after_days: Some(30),
+    };
+    archive.compact(&policy, monday + 30 * DAY);
+
+    assert_eq!(archive.benches.len(), 1);
+    let measurements = &archive.benches[0].measurements;
+    let values: Vec<f32> = measurements.iter().map(|x| x.value).collect();
+    assert_eq!(values, vec![1.0, 2.0, 10.0, 20.0, 30.0]);
+
+    // Measurements without a run time are kept as is
+    assert!(measurements[0].metadata.is_none());
+    // The first week is aggregated
+    let week = &measurements[1];
+    assert!(week.samples.is_none());
+    assert_eq!(week.stats.as_ref().unwrap().min, 1.0);
+    assert_eq!(week.stats.as_ref().unwrap().max, 3.0);
+    assert_eq!(
+        week.metadata.as_ref().unwrap().run_time,
+        Some(monday + 6 * DAY)
+    );
+    // The week that the most recent runs are in is not aggregated until all of it is old
+    assert!(measurements[2].samples.is_some());
+    // The most recent runs are untouched
+    assert!(measurements[3].samples.is_some());
+    assert!(measurements[4].samples.is_some());
+
+    // Compacting is idempotent
+    archive.compact(&policy, monday + 30 * DAY);
+    let again: Vec<f32> = archive.benches[0]
+        .measurements
+        .iter()
+        .map(|x| x.value)
+        .collect();
+    assert_eq!(again, values);
+}
+
+#[test]
+fn test_compact_every_run() {
+    const DAY: i64 = 24 * 60 * 60;
+    // 1970-01-05 was a monday
+    let monday = 4 * DAY;
+    let policy = RetentionPolicy {
+        full_resolution_runs: 2,
+        aggregate: AggregatePeriod::Weekly,
+        drop_after_days: None,
+    };
+    let runs: Vec<(f32, i64)> = (0..17)
+        .map(|day| (day as f32, monday + day * DAY))
+        .collect();
+
+    // Compacting after every run, as CI does, must give the same history as compacting once at the end
+    let mut every_run = BenchArchive::new("compact".to_owned(), vec![]);
+    let mut once = BenchArchive::new("compact".to_owned(), vec![]);
+    for (value, time) in runs {
+        let run = || archive(vec![("bench", "x86", measurement_at(value, time))]);
+        every_run.insert(run());
+        every_run.compact(&policy, time);
+        once.insert(run());
+    }
+    once.compact(&policy, monday + 16 * DAY);
+
+    for history in [&every_run, &once] {
+        let measurements = &history.benches[0].measurements;
+        let values: Vec<f32> = measurements.iter().map(|x| x.value).collect();
+        // Each full week is aggregated to its median, the current week is still in the window
+        assert_eq!(values, vec![3.0, 10.0, 14.0, 15.0, 16.0]);
+        let week = measurements[0].stats.as_ref().unwrap();
+        assert_eq!((week.min, week.max), (0.0, 6.0));
+        let week = measurements[1].stats.as_ref().unwrap();
+        assert_eq!((week.min, week.max), (7.0, 13.0));
+    }
+}
+
+#[test]
+fn test_rename() {
+    let mut history = archive(vec![
+        ("old name", "x86", BenchMeasurement::new(1.0)),
+        ("new name", "x86", BenchMeasurement::new(2.0)),
+        ("old name", "arm", BenchMeasurement::new(3.0)),
+    ]);
+    history.benches.push(Bench::new(
+        "no machine".to_owned(),
+        HashMap::new(),
+        vec![BenchMeasurement::new(4.0)],
+    ));
+
+    let renames = vec![
+        Rename {
+            from_name: "old name".to_owned(),
+            from_keys: HashMap::from([("machine".to_owned(), "x86".to_owned())]),
+            to_name: Some("new name".to_owned()),
+            to_keys: HashMap::new(),
+        },
+        Rename {
+            from_name: "no machine".to_owned(),
+            from_keys: HashMap::new(),
+            to_name: None,
+            to_keys: HashMap::from([("machine".to_owned(), "x86".to_owned())]),
+        },
+        // Does not replace the existing machine key of the arm series
+        Rename {
+            from_name: "old name".to_owned(),
+            from_keys: HashMap::new(),
+            to_name: None,
+            to_keys: HashMap::from([("machine".to_owned(), "x86".to_owned())]),
+        },
+    ];
+    assert_eq!(history.rename(&renames), 2);
+
+    let series: Vec<(String, String, Vec<f32>)> = history
+        .benches
+        .iter()
+        .map(|bench| {
+            (
+                bench.name.clone(),
+                key(bench, "machine"),
+                bench.measurements.iter().map(|x| x.value).collect(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        series,
+        vec![
+            ("new name".to_owned(), "x86".to_owned(), vec![1.0, 2.0]),
+            ("old name".to_owned(), "arm".to_owned(), vec![3.0]),
+            ("no machine".to_owned(), "x86".to_owned(), vec![4.0]),
+        ]
+    );
+}
+
+#[test]
+fn test_retire() {
+    let mut history = archive(vec![
+        ("kept", "x86", BenchMeasurement::new(1.0)),
+        ("removed", "x86", BenchMeasurement::new(1.0)),
+    ]);
+    for _ in 0..3 {
+        history.insert(archive(vec![("kept", "x86", BenchMeasurement::new(1.0))]));
+    }
+    history.retire(3);
+    assert!(!history.benches[0].retired);
+    assert_eq!(history.benches[1].missed_runs, 3);
+    assert!(history.benches[1].retired);
+
+    // A retired bench that runs again is no longer retired
+    history.insert(archive(vec![(
+        "removed",
+        "x86",
+        BenchMeasurement::new(1.0),
+    )]));
+    assert!(!history.benches[1].retired);
+    assert_eq!(history.benches[1].missed_runs, 0);
+}
+
+#[test]
+fn test_json_round_trip() {
+    let archive = BenchArchive::load("tests/golden/v3.cbor").unwrap();
+    let json = archive.to_json().unwrap();
+    let imported = BenchArchive::from_json(&json).unwrap();
+    assert_eq!(imported.to_json().unwrap(), json);
+    assert_eq!(
+        imported.benches[1].measurements[0]
+            .finding
+            .as_ref()
+            .unwrap()
+            .kind,
+        FindingKind::Regression
+    );
+}
+
+#[test]
+fn test_json_import_upgrades_old_versions() {
+    let json = r#"{
+        "version": 0,
+        "reset_id": 0,
+        "title": "old",
+        "benches": [{ "name": "bench", "keys": {}, "measurements": [{ "value": 2 }] }]
+    }"#;
+    let archive = BenchArchive::from_json(json).unwrap();
+    assert_eq!(archive.benches[0].measurements[0].value, 2.0);
+    assert!(!archive.benches[0].retired);
+}
+
+#[test]
+fn test_csv_round_trip() {
+    let mut archive = run("x86", 1, 1.5);
+    archive.benches[0].name = "needs, \"quoting\"".to_owned();
+    archive.insert(archive_with_samples());
+
+    let csv = archive.to_csv();
+    assert_eq!(
+        csv.lines().next().unwrap(),
+        "name,key:machine,value,samples,commit,branch,commit_time,run_time,toolchain,runner"
+    );
+    assert_eq!(
+        csv.lines().nth(1).unwrap(),
+        "\"needs, \"\"quoting\"\"\",x86,1.5,,commit1,,1,11,,"
+    );
+
+    let imported = BenchArchive::from_csv("compare".to_owned(), &csv).unwrap();
+    assert_eq!(imported.to_csv(), csv);
+    let samples = &imported.benches[1].measurements[0];
+    assert_eq!(samples.samples, Some(vec![1.0, 2.0, 3.0]));
+    assert_eq!(samples.stats.as_ref().unwrap().median, 2.0);
+    assert!(samples.metadata.is_none());
+}
+
+fn archive_with_samples() -> BenchArchive {
+    archive(vec![(
+        "samples",
+        "arm",
+        BenchMeasurement::with_samples(2.0, vec![1.0, 2.0, 3.0]),
+    )])
+}
+
+#[test]
+fn test_csv_errors() {
+    let err = BenchArchive::from_csv("x".to_owned(), "name,value\n")
+        .err()
+        .unwrap();
+    assert!(format!("{err}").contains("samples"), "{err}");
+
+    let csv = "name,value,samples,commit,branch,commit_time,run_time,toolchain,runner\nbench,fast,,,,,,,\n";
+    let err = BenchArchive::from_csv("x".to_owned(), csv).err().unwrap();
+    assert!(format!("{err}").contains("Row 2"), "{err}");
+}
+
+#[test]
+fn test_import_criterion() {
+    let archive = BenchArchive::from_criterion_dir(
+        "criterion".to_owned(),
+        std::path::Path::new("tests/criterion"),
+        "new",
+        "x86",
+    )
+    .unwrap();
+
+    let names: Vec<&str> = archive.benches.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, vec!["mat4 mul vec4", "vec3/normalize"]);
+    for bench in &archive.benches {
+        assert_eq!(key(bench, "machine"), "x86");
+        assert_eq!(key(bench, "type"), "walltime (ns)");
+    }
+
+    // Linear sampling uses the slope
+    let mat4 = &archive.benches[0].measurements[0];
+    assert_eq!(mat4.value, 1.2299);
+    assert_eq!(mat4.samples, Some(vec![1.24, 1.23, 1.23, 1.23]));
+    assert!(mat4.metadata.as_ref().unwrap().run_time.is_some());
+
+    // Flat sampling uses the mean
+    let vec3 = &archive.benches[1].measurements[0];
+    assert_eq!(vec3.value, 3.0);
+    assert_eq!(vec3.samples, Some(vec![2.9, 3.0, 3.1]));
+
+    let base = BenchArchive::from_criterion_dir(
+        "criterion".to_owned(),
+        std::path::Path::new("tests/criterion"),
+        "base",
+        "x86",
+    )
+    .unwrap();
+    assert_eq!(base.benches.len(), 1);
+    assert_eq!(base.benches[0].measurements[0].value, 1.5);
+
+    let missing = BenchArchive::from_criterion_dir(
+        "criterion".to_owned(),
+        std::path::Path::new("tests/criterion"),
+        "missing",
+        "x86",
+    )
+    .err()
+    .unwrap();
+    assert!(
+        matches!(&missing, ArchiveError::NoCriterionResults { baseline, .. } if baseline == "missing"),
+        "unexpected error: {missing:?}"
+    );
+}
+
+#[test]
+fn test_compact_round_trip() {
+    let archive = BenchArchive::load("tests/golden/v3.cbor").unwrap();
+    let compact = archive.to_compact().unwrap();
+    let loaded = BenchArchive::load_from_cbor(&compact).unwrap();
+    assert_eq!(loaded.to_json().unwrap(), archive.to_json().unwrap());
+
+    let err = BenchArchive::load_from_cbor(&compact[..compact.len() / 2])
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, ArchiveError::Corrupt { .. }),
+        "unexpected error: {err:?}"
+    );
+}
+
+#[test]
+fn test_compact_decompression_bomb() {
+    // A single fixed huffman deflate block of a literal 0 followed by repeated copies of the previous 258 bytes.
+    // Each copy takes 13 bits, so these 2MB decompress to over 300MB.
+    let mut bits = BitWriter::default();
+    bits.write(0b1, 1); // BFINAL
+    bits.write(0b01, 2); // BTYPE fixed huffman
+    bits.write_huffman(0b00110000, 8); // literal 0
+    for _ in 0..1_200_000 {
+        bits.write_huffman(0b11000101, 8); // length 258
+        bits.write_huffman(0b00000, 5); // distance 1
+    }
+    bits.write_huffman(0b0000000, 7); // end of block
+
+    let mut bytes = b"ussal-compact\0".to_vec();
+    bytes.extend(bits.bytes);
+    let err = BenchArchive::load_from_cbor(&bytes).err().unwrap();
+    assert!(
+        matches!(&err, ArchiveError::Corrupt { reason } if reason.starts_with("Decompresses to more than")),
+        "unexpected error: {err:?}"
+    );
+}
+
+/// Writes bits in the order deflate expects, least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: usize) {
+        for i in 0..count {
+            if self.len == self.bytes.len() * 8 {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+
+    /// Huffman codes are written most significant bit first
+    fn write_huffman(&mut self, code: u32, count: usize) {
+        for i in (0..count).rev() {
+            self.write(code >> i & 1, 1);
+        }
+    }
+}
+
+#[test]
+fn test_compact_size() {
+    let mut history = BenchArchive::new("size".to_owned(), vec![]);
+    for run in 0..200 {
+        let metadata = RunMetadata {
+            commit: Some(format!("{run:040x}")),
+            branch: Some("main".to_owned()),
+            commit_time: Some(1_700_000_000 + run * 3600),
+            run_time: Some(1_700_000_100 + run * 3600),
+            toolchain: Some("rustc 1.76.0 (07dca489a 2024-02-04)".to_owned()),
+            runner: Some("Raspberry Pi 4 Model B Rev 1.4, Linux 6.1.21-v8+".to_owned()),
+        };
+        let benches = (0..20)
+            .map(|bench| {
+                let value = 1000.0 + bench as f32 * 10.0 + (run % 7) as f32;
+                Bench::new(
+                    format!("bench group/bench {bench}"),
+                    HashMap::from([
+                        ("machine".to_owned(), "aarch64-linux-rpi4".to_owned()),
+                        ("type".to_owned(), "walltime (ns)".to_owned()),
+                    ]),
+                    vec![BenchMeasurement::new(value).with_metadata(metadata.clone())],
+                )
+            })
+            .collect();
+        history.insert(BenchArchive::new("size".to_owned(), benches));
+    }
+
+    let plain = serde_cbor::to_vec(&history).unwrap();
+    let compact = history.to_compact().unwrap();
+    assert!(
+        compact.len() * 10 < plain.len(),
+        "compact {} bytes, plain {} bytes",
+        compact.len(),
+        plain.len()
+    );
+    let loaded = BenchArchive::load_from_cbor(&compact).unwrap();
+    assert_eq!(loaded.to_json().unwrap(), history.to_json().unwrap());
+}
+
+fn query_history() -> BenchArchive {
+    let mut history = run("x86", 1, 1.0);
+    for (machine, commit_time, value) in [
+        ("x86", 2, 2.0),
+        ("x86", 3, 3.0),
+        ("arm", 1, 10.0),
+        ("arm", 3, 30.0),
+    ] {
+        history.merge(run(machine, commit_time, value)).unwrap();
+    }
+    history
+        .merge(archive(vec![(
+            "vec3 dot",
+            "x86",
+            BenchMeasurement::new(5.0),
+        )]))
+        .unwrap();
+    history
+}
+
+fn selected(series: &[Series<'_>]) -> Vec<(String, String, Vec<f32>)> {
+    series
+        .iter()
+        .map(|series| {
+            (
+                series.bench.name.clone(),
+                key(series.bench, "machine"),
+                series.measurements.iter().map(|x| x.value).collect(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_query_select() {
+    let mut history = query_history();
+    let names = |history: &BenchArchive, query: Query| -> Vec<(String, String)> {
+        selected(&history.query(&query))
+            .into_iter()
+            .map(|(name, machine, _)| (name, machine))
+            .collect()
+    };
+    let pair = |name: &str, machine: &str| (name.to_owned(), machine.to_owned());
+
+    assert_eq!(names(&history, Query::default()).len(), 3);
+    assert_eq!(
+        names(&history, Query::default().with_name_glob("vec3*")),
+        vec![pair("vec3 dot", "x86")]
+    );
+    assert_eq!(
+        names(&history, Query::default().with_name_glob("b?n*h")),
+        vec![pair("bench", "x86"), pair("bench", "arm")]
+    );
+    assert!(names(&history, Query::default().with_name_glob("bench?")).is_empty());
+    assert_eq!(
+        names(
+            &history,
+            Query::default()
+                .with_name("bench")
+                .with_key("machine", KeyPredicate::Equals("arm".to_owned()))
+        ),
+        vec![pair("bench", "arm")]
+    );
+    assert_eq!(
+        names(
+            &history,
+            Query::default().with_key("machine", KeyPredicate::NoneOf(vec!["x86".to_owned()]))
+        ),
+        vec![pair("bench", "arm")]
+    );
+    assert_eq!(
+        names(
+            &history,
+            Query::default().with_key(
+                "machine",
+                KeyPredicate::OneOf(vec!["x86".to_owned(), "riscv".to_owned()])
+            )
+        )
+        .len(),
+        2
+    );
+    assert_eq!(
+        names(
+            &history,
+            Query::default().with_key("type", KeyPredicate::Absent)
+        )
+        .len(),
+        3
+    );
+    assert!(names(
+        &history,
+        Query::default().with_key("type", KeyPredicate::Present)
+    )
+    .is_empty());
+
+    history.benches[1].retired = true;
+    assert_eq!(names(&history, Query::default().without_retired()).len(), 2);
+}
+
+#[test]
+fn test_query_slice() {
+    let history = query_history();
+
+    // Run times are 10 seconds after commit times
+    let since = history.query(&Query::default().with_time_range(Some(12), None));
+    assert_eq!(
+        selected(&since),
+        vec![
+            ("bench".to_owned(), "x86".to_owned(), vec![2.0, 3.0]),
+            ("bench".to_owned(), "arm".to_owned(), vec![30.0]),
+        ]
+    );
+    assert_eq!(since[0].offset, 1);
+    assert_eq!(since[1].offset, 1);
+
+    let until = history.query(&Query::default().with_time_range(None, Some(12)));
+    assert_eq!(
+        selected(&until),
+        vec![
+            ("bench".to_owned(), "x86".to_owned(), vec![1.0, 2.0]),
+            ("bench".to_owned(), "arm".to_owned(), vec![10.0]),
+        ]
+    );
+
+    let commits =
+        history.query(&Query::default().with_commit_range(Some("commit2"), Some("commit3")));
+    assert_eq!(
+        selected(&commits),
+        vec![
+            ("bench".to_owned(), "x86".to_owned(), vec![2.0, 3.0]),
+            ("bench".to_owned(), "arm".to_owned(), vec![30.0]),
+        ]
+    );
+
+    assert!(history
+        .query(&Query::default().with_commit_range(Some("unknown"), None))
+        .is_empty());
+}
+
+#[test]
+fn test_key_values() {
+    let history = query_history();
+    let key_values = history.key_values();
+    assert_eq!(key_values.len(), 1);
+    assert_eq!(
+        key_values["machine"].iter().copied().collect::<Vec<_>>(),
+        vec!["arm", "x86"]
+    );
+}
+
+#[test]
+fn test_aggregate() {
+    let history = query_history();
+    let series = history.query(&Query::default().with_name("bench"));
+
+    let median = aggregate(&series, "machine", Aggregation::Median);
+    assert_eq!(median.len(), 1);
+    assert_eq!(median[0].name, "bench");
+    assert!(median[0].keys.is_empty());
+    assert_eq!(median[0].series, 2);
+    let points: Vec<(Option<String>, f32, usize)> = median[0]
+        .points
+        .iter()
+        .map(|x| (x.commit.clone(), x.value, x.count))
+        .collect();
+    assert_eq!(
+        points,
+        vec![
+            (Some("commit1".to_owned()), 5.5, 2),
+            (Some("commit2".to_owned()), 2.0, 1),
+            (Some("commit3".to_owned()), 16.5, 2),
+        ]
+    );
+
+    let max = aggregate(&series, "machine", Aggregation::Max);
+    let values: Vec<f32> = max[0].points.iter().map(|x| x.value).collect();
+    assert_eq!(values, vec![10.0, 2.0, 30.0]);
+
+    // Aggregating by a key that the series do not share keeps them apart
+    let all = history.query(&Query::default());
+    assert_eq!(aggregate(&all, "type", Aggregation::Mean).len(), 3);
+}
+
+fn baseline_values(archive: &BenchArchive, name: &str) -> Vec<(String, String, f32)> {
+    archive
+        .baseline(name)
+        .unwrap()
+        .benches
+        .iter()
+        .map(|bench| {
+            (
+                bench.name.clone(),
+                key(bench, "machine"),
+                bench.measurements[0].value,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_baselines() {
+    let mut history = run("x86", 1, 10.0);
+    history.merge(run("arm", 1, 20.0)).unwrap();
+    history.pin_baseline("v1.0", 100);
+    history.merge(run("x86", 2, 12.0)).unwrap();
+    history.merge(run("arm", 2, 30.0)).unwrap();
+
+    assert_eq!(history.baseline("v1.0").unwrap().pinned_at, Some(100));
+    assert_eq!(
+        baseline_values(&history, "v1.0"),
+        vec![
+            ("bench".to_owned(), "x86".to_owned(), 10.0),
+            ("bench".to_owned(), "arm".to_owned(), 20.0),
+        ]
+    );
+
+    // Later runs are compared against the pinned values rather than the previous run
+    let baseline = history.baseline_archive("v1.0").unwrap();
+    let verdicts: Vec<(String, Verdict)> = compare(&baseline, &history, &Query::default())
+        .iter()
+        .map(|x| (x.keys_string(), x.verdict(0.05)))
+        .collect();
+    assert_eq!(
+        verdicts,
+        vec![
+            ("machine=arm".to_owned(), Verdict::Regressed),
+            ("machine=x86".to_owned(), Verdict::Regressed),
+        ]
+    );
+
+    // Pinning again replaces the baseline, and retired series are left out
+    history.benches[1].retired = true;
+    history.pin_baseline("v1.0", 200);
+    assert_eq!(history.baselines.len(), 1);
+    assert_eq!(
+        baseline_values(&history, "v1.0"),
+        vec![("bench".to_owned(), "x86".to_owned(), 12.0)]
+    );
+
+    assert!(history.remove_baseline("v1.0"));
+    assert!(!history.remove_baseline("v1.0"));
+    assert!(history.baseline_archive("v1.0").is_none());
+}
+
+#[test]
+fn test_baselines_are_kept() {
+    let mut history = archive(vec![("old name", "x86", BenchMeasurement::new(1.0))]);
+    history.pin_baseline("v1.0", 100);
+
+    let mut other = archive(vec![("old name", "arm", BenchMeasurement::new(2.0))]);
+    other.pin_baseline("v1.0", 200);
+    other.pin_baseline("v2.0", 300);
+    history.merge(other).unwrap();
+    // The existing baseline of the same name is kept
+    let baselines: Vec<(&str, Option<i64>)> = history
+        .baselines
+        .iter()
+        .map(|x| (x.name.as_str(), x.pinned_at))
+        .collect();
+    assert_eq!(baselines, vec![("v1.0", Some(100)), ("v2.0", Some(300))]);
+
+    history.rename(&[Rename {
+        from_name: "old name".to_owned(),
+        from_keys: HashMap::new(),
+        to_name: Some("new name".to_owned()),
+        to_keys: HashMap::new(),
+    }]);
+    assert_eq!(
+        baseline_values(&history, "v1.0"),
+        vec![("new name".to_owned(), "x86".to_owned(), 1.0)]
+    );
+
+    let loaded = BenchArchive::load_from_cbor(&history.to_compact().unwrap()).unwrap();
+    assert_eq!(loaded.to_json().unwrap(), history.to_json().unwrap());
+    let loaded = BenchArchive::from_json(&history.to_json().unwrap()).unwrap();
+    assert_eq!(loaded.baselines.len(), 2);
+}
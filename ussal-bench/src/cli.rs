@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+
+/// The arguments a bench binary accepts.
+///
+/// `cargo bench` and the ussal runner both invoke bench binaries with the same flags they would give to criterion.
+/// So we accept that same interface, ignoring any criterion flags that have no meaning to us.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Only run benches whose name contains this string
+    pub filter: Option<String>,
+    /// When set the filter must match the bench name exactly
+    pub exact: bool,
+    /// List benches instead of running them
+    pub list: bool,
+    /// Run each bench routine exactly this many times without measuring anything.
+    /// Used by external tools that take their own measurements of the whole process.
+    pub iterations: Option<u64>,
+}
+
+/// criterion flags that are accepted but have no effect on ussal-bench
+const IGNORED_FLAGS: &[&str] = &[
+    "--bench",
+    "--noplot",
+    "--discard-baseline",
+    "--nocapture",
+    "--show-output",
+    "--quiet",
+    "--verbose",
+];
+
+impl Args {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args> {
+        let mut result = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--list" => result.list = true,
+                "--exact" => result.exact = true,
+                "--iterations" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--iterations requires a value"))?;
+                    result.iterations = Some(value.parse().map_err(|_| {
+                        anyhow!("--iterations expected an integer but was {value:?}")
+                    })?);
+                }
+                flag if IGNORED_FLAGS.contains(&flag) => {}
+                flag if flag.starts_with('-') => return Err(anyhow!("Unknown flag {flag:?}")),
+                filter => {
+                    if result.filter.is_some() {
+                        return Err(anyhow!("Only one filter can be specified"));
+                    }
+                    result.filter = Some(filter.to_owned());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn matches(&self, bench_name: &str) -> bool {
+        match &self.filter {
+            Some(filter) if self.exact => filter == bench_name,
+            Some(filter) => bench_name.contains(filter.as_str()),
+            None => true,
+        }
+    }
+}
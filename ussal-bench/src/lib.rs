@@ -0,0 +1,252 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+
+//! A benchmark harness intended to be run by ussal-server.
+//!
+//! Benches are registered in the same way as criterion benches, and the resulting binary accepts the same command line interface as a criterion bench binary.
+//! This allows the ussal runner to run benches without caring which harness was used.
+
+mod cli;
+pub mod report;
+
+use cli::Args;
+use report::{BenchReport, MetricReport, Summary};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub use std::hint::black_box;
+
+/// When set, a line of json describing each bench result is appended to the file at this path.
+pub const RESULTS_PATH_ENV: &str = "USSAL_RESULTS_PATH";
+
+/// Registers and runs benches.
+/// Configuration methods follow the builder pattern and are intended to be used with `bench_group!`
+pub struct Harness {
+    args: Args,
+    sample_size: usize,
+    warm_up_time: Duration,
+    measurement_time: Duration,
+    results_path: Option<PathBuf>,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Harness {
+            args: Args::default(),
+            sample_size: 50,
+            warm_up_time: Duration::from_secs(1),
+            measurement_time: Duration::from_secs(3),
+            results_path: std::env::var_os(RESULTS_PATH_ENV).map(PathBuf::from),
+        }
+    }
+}
+
+impl Harness {
+    /// The number of samples collected for each bench, must be at least 2.
+    pub fn sample_size(mut self, sample_size: usize) -> Self {
+        assert!(sample_size >= 2, "sample_size must be at least 2");
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// How long to run each bench before starting measurements
+    pub fn warm_up_time(mut self, warm_up_time: Duration) -> Self {
+        self.warm_up_time = warm_up_time;
+        self
+    }
+
+    /// The approximate total time to spend measuring each bench
+    pub fn measurement_time(mut self, measurement_time: Duration) -> Self {
+        self.measurement_time = measurement_time;
+        self
+    }
+
+    /// Append a json line describing each bench result to this file.
+    /// Defaults to the value of the `USSAL_RESULTS_PATH` environment variable.
+    pub fn results_path(mut self, results_path: impl Into<PathBuf>) -> Self {
+        self.results_path = Some(results_path.into());
+        self
+    }
+
+    /// Apply the arguments the bench binary was invoked with.
+    /// Exits the process if they are invalid.
+    pub fn configure_from_args(mut self) -> Self {
+        match Args::parse(std::env::args().skip(1)) {
+            Ok(args) => self.args = args,
+            Err(err) => {
+                eprintln!("error: {err}");
+                std::process::exit(1);
+            }
+        }
+        self
+    }
+
+    pub fn bench_function<F>(&mut self, name: &str, mut f: F) -> &mut Self
+    where
+        F: FnMut(&mut Bencher),
+    {
+        if !self.args.matches(name) {
+            return self;
+        }
+
+        if self.args.list {
+            println!("{name}: benchmark");
+            return self;
+        }
+
+        if let Some(iterations) = self.args.iterations {
+            let mut bencher = Bencher::new(iterations);
+            f(&mut bencher);
+            bencher.assert_iterated(name);
+            return self;
+        }
+
+        let samples = self.measure(name, &mut f);
+        let summary = Summary::from_samples(&samples).unwrap();
+        println!(
+            "{name:<24} time:   [{:.4} ns {:.4} ns {:.4} ns]",
+            summary.min, summary.median, summary.max
+        );
+
+        self.write_report(BenchReport {
+            bench_name: name.to_owned(),
+            metrics: vec![MetricReport {
+                kind: "walltime".to_owned(),
+                unit: "ns".to_owned(),
+                samples,
+                summary,
+            }],
+        });
+        self
+    }
+
+    /// Returns the walltime in nanoseconds of a single iteration for each sample
+    fn measure<F>(&self, name: &str, f: &mut F) -> Vec<f64>
+    where
+        F: FnMut(&mut Bencher),
+    {
+        // Keep doubling the iteration count until warmup is complete, this also gives us an estimate of the time per iteration.
+        let mut iterations = 1;
+        let mut total_iterations = 0;
+        let mut total_elapsed = Duration::ZERO;
+        while total_elapsed < self.warm_up_time {
+            let mut bencher = Bencher::new(iterations);
+            f(&mut bencher);
+            bencher.assert_iterated(name);
+            total_iterations += iterations;
+            total_elapsed += bencher.elapsed;
+            iterations *= 2;
+        }
+        let nanos_per_iteration =
+            (total_elapsed.as_nanos() as f64 / total_iterations as f64).max(1.0);
+        let nanos_per_sample = self.measurement_time.as_nanos() as f64 / self.sample_size as f64;
+        let iterations_per_sample = ((nanos_per_sample / nanos_per_iteration) as u64).max(1);
+
+        (0..self.sample_size)
+            .map(|_| {
+                let mut bencher = Bencher::new(iterations_per_sample);
+                f(&mut bencher);
+                bencher.elapsed.as_nanos() as f64 / iterations_per_sample as f64
+            })
+            .collect()
+    }
+
+    fn write_report(&self, report: BenchReport) {
+        if let Some(path) = &self.results_path {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| panic!("Failed to open results file {path:?}: {e}"));
+            let mut line = serde_json::to_vec(&report).unwrap();
+            line.push(b'\n');
+            file.write_all(&line)
+                .unwrap_or_else(|e| panic!("Failed to write to results file {path:?}: {e}"));
+        }
+    }
+}
+
+/// Passed to each bench function to time the routine under test.
+pub struct Bencher {
+    iterations: u64,
+    elapsed: Duration,
+    iterated: bool,
+}
+
+impl Bencher {
+    fn new(iterations: u64) -> Self {
+        Bencher {
+            iterations,
+            elapsed: Duration::ZERO,
+            iterated: false,
+        }
+    }
+
+    /// Time the routine, the harness decides how many times it is called.
+    pub fn iter<O, R>(&mut self, mut routine: R)
+    where
+        R: FnMut() -> O,
+    {
+        let start = Instant::now();
+        for _ in 0..self.iterations {
+            black_box(routine());
+        }
+        self.elapsed = start.elapsed();
+        self.iterated = true;
+    }
+
+    fn assert_iterated(&self, name: &str) {
+        assert!(
+            self.iterated,
+            "The bench {name:?} must call Bencher::iter to run the routine under test"
+        );
+    }
+}
+
+/// Defines a function that runs a group of benches.
+///
+/// ```ignore
+/// bench_group!(benches, bench_a, bench_b);
+/// // or with a custom config
+/// bench_group!(
+///     name = benches;
+///     config = Harness::default().sample_size(10);
+///     targets = bench_a, bench_b
+/// );
+/// ```
+#[macro_export]
+macro_rules! bench_group {
+    (name = $name:ident; config = $config:expr; targets = $( $target:path ),+ $(,)*) => {
+        pub fn $name() {
+            let mut harness: $crate::Harness = ($config).configure_from_args();
+            $(
+                $target(&mut harness);
+            )+
+        }
+    };
+    ($name:ident, $( $target:path ),+ $(,)*) => {
+        $crate::bench_group!(
+            name = $name;
+            config = $crate::Harness::default();
+            targets = $( $target ),+
+        );
+    };
+}
+
+/// Defines the main function of the bench binary, running all of the provided groups.
+///
+/// ```ignore
+/// bench_main!(benches);
+/// ```
+#[macro_export]
+macro_rules! bench_main {
+    ( $( $group:path ),+ $(,)* ) => {
+        fn main() {
+            $(
+                $group();
+            )+
+        }
+    };
+}
@@ -0,0 +1,27 @@
+//! The machine readable format bench binaries use to report results to the ussal runner.
+//!
+//! The runner creates an empty file and passes its path to the bench binary in the `USSAL_RESULTS_PATH` environment variable.
+//! The bench binary then appends a [`BenchReport`] to the file for each bench it runs, serialized as a single line of json.
+//! Appending a line per bench means the results of benches that completed before a crash are not lost.
+
+use serde::{Deserialize, Serialize};
+pub use ussal_stats::Summary;
+
+/// The results of a single bench.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub bench_name: String,
+    pub metrics: Vec<MetricReport>,
+}
+
+/// A single kind of measurement taken of a bench
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricReport {
+    /// e.g. `walltime`
+    pub kind: String,
+    /// e.g. `ns`
+    pub unit: String,
+    /// Each sample is the value measured for a single iteration of the bench, averaged across all iterations in that sample.
+    pub samples: Vec<f64>,
+    pub summary: Summary,
+}
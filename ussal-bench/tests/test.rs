@@ -0,0 +1,49 @@
+use std::time::Duration;
+use ussal_bench::report::{BenchReport, Summary};
+use ussal_bench::Harness;
+
+#[test]
+fn test_summary() {
+    let summary = Summary::from_samples(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+    assert_eq!(summary.mean, 2.5);
+    assert_eq!(summary.median, 2.5);
+    assert_eq!(summary.min, 1.0);
+    assert_eq!(summary.max, 4.0);
+    assert!((summary.std_dev - 1.2909944).abs() < 0.0001);
+
+    assert!(Summary::from_samples(&[]).is_none());
+}
+
+#[test]
+fn test_results_file() {
+    let path = std::env::temp_dir().join("ussal-bench-test-results.jsonl");
+    std::fs::remove_file(&path).ok();
+
+    Harness::default()
+        .sample_size(5)
+        .warm_up_time(Duration::from_millis(10))
+        .measurement_time(Duration::from_millis(10))
+        .results_path(&path)
+        .bench_function("sum", |b| b.iter(|| (0..100u64).sum::<u64>()))
+        .bench_function("product", |b| b.iter(|| (1..10u64).product::<u64>()));
+
+    let reports: Vec<BenchReport> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0].bench_name, "sum");
+    assert_eq!(reports[1].bench_name, "product");
+    for report in &reports {
+        assert_eq!(report.metrics.len(), 1);
+        let metric = &report.metrics[0];
+        assert_eq!(metric.kind, "walltime");
+        assert_eq!(metric.unit, "ns");
+        assert_eq!(metric.samples.len(), 5);
+        assert!(metric.summary.min <= metric.summary.median);
+        assert!(metric.summary.median <= metric.summary.max);
+    }
+}
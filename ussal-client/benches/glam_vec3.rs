@@ -0,0 +1,62 @@
+// Benched with ussal-bench instead of criterion to exercise both harnesses.
+
+mod support;
+
+use glam::Vec3;
+use support::*;
+use ussal_bench::{bench_group, bench_main, black_box, Harness};
+
+const SIZE: usize = 1 << 13;
+
+fn vec3_dot(harness: &mut Harness) {
+    let mut rng = support::PCG32::default();
+    let inputs = black_box(
+        (0..SIZE)
+            .map(|_| (random_vec3(&mut rng), random_vec3(&mut rng)))
+            .collect::<Vec<(Vec3, Vec3)>>(),
+    );
+    let mut i = 0;
+    harness.bench_function("vec3 dot", |b| {
+        b.iter(|| {
+            i = (i + 1) & (SIZE - 1);
+            let (a, b) = inputs[i];
+            a.dot(b)
+        })
+    });
+}
+
+fn vec3_cross(harness: &mut Harness) {
+    let mut rng = support::PCG32::default();
+    let inputs = black_box(
+        (0..SIZE)
+            .map(|_| (random_vec3(&mut rng), random_vec3(&mut rng)))
+            .collect::<Vec<(Vec3, Vec3)>>(),
+    );
+    let mut i = 0;
+    harness.bench_function("vec3 cross", |b| {
+        b.iter(|| {
+            i = (i + 1) & (SIZE - 1);
+            let (a, b) = inputs[i];
+            a.cross(b)
+        })
+    });
+}
+
+fn vec3_normalize(harness: &mut Harness) {
+    let mut rng = support::PCG32::default();
+    let inputs = black_box(
+        (0..SIZE)
+            .map(|_| random_nonzero_vec3(&mut rng))
+            .collect::<Vec<Vec3>>(),
+    );
+    let mut i = 0;
+    harness.bench_function("vec3 normalize", |b| {
+        b.iter(|| {
+            i = (i + 1) & (SIZE - 1);
+            inputs[i].normalize()
+        })
+    });
+}
+
+bench_group!(benches, vec3_dot, vec3_cross, vec3_normalize);
+bench_main!(benches);
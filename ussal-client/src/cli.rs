@@ -1,7 +1,11 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Address of the ussal orchestrator web socket endpoint
     /// e.g. wss://some-ussal-instance.com/run_job
     /// Overrides the address specified in the ussal.json
@@ -9,15 +13,120 @@ pub struct Args {
     pub address: Option<String>,
 
     /// Authorization token
-    #[clap(long)]
-    pub auth_token: uuid::Uuid,
+    #[clap(long, required = true)]
+    pub auth_token: Option<uuid::Uuid>,
 
     /// Authorization token
     #[clap(long)]
     pub ci: bool,
 
+    /// The branch or PR that a `--ci` run belongs to e.g. `main` or `pr-123`.
+    /// Results are written to `bench_ci_web_root/$branch/`.
+    /// Defaults to the current git branch.
+    #[clap(long)]
+    pub branch: Option<String>,
+
+    /// The branch that other branches are compared against
+    #[clap(long, default_value = "main")]
+    pub main_branch: String,
+
     /// Path to the ussal json config file.
     /// By default reads from `ussal.json`
     #[clap(long)]
     pub config_path: Option<String>,
+
+    /// Write a markdown summary comparing this run against the history in `--baseline-history` to this path.
+    /// Intended to be posted as a PR comment.
+    #[clap(long)]
+    pub markdown_report: Option<String>,
+
+    /// The history that `--markdown-report` compares against.
+    /// By default reads the `--main-branch` history from `bench_ci_web_root`
+    #[clap(long)]
+    pub baseline_history: Option<String>,
+
+    /// A link to the web viewer to include in the `--markdown-report`
+    #[clap(long)]
+    pub viewer_url: Option<String>,
+
+    /// After a `--ci` run, pin its results in the history as the baseline of this name e.g. `v1.0`.
+    /// Replaces any existing baseline of the same name.
+    #[clap(long)]
+    pub pin_baseline: Option<String>,
+
+    /// Compare a `--ci` run against the baseline of this name pinned in the history, in addition to the usual comparison against recent runs.
+    /// The comparison is logged and appended to the summary.
+    #[clap(long)]
+    pub compare_baseline: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Merge the archives produced by parallel CI workflows into a single history
+    Merge {
+        /// Archives to merge, in addition to the existing `--output` archive if it exists
+        #[clap(required = true)]
+        inputs: Vec<String>,
+
+        /// Where to write the merged archive, if it already exists its contents are kept
+        #[clap(long)]
+        output: String,
+    },
+    /// Convert an archive to json or csv, the format is chosen by the extension of `output`
+    Export {
+        /// The archive to export
+        input: String,
+
+        /// A path ending in `.json` or `.csv`
+        output: String,
+    },
+    /// Convert a json or csv file produced by `export` back into an archive, the format is chosen by the extension of `input`
+    Import {
+        /// A path ending in `.json` or `.csv`
+        input: String,
+
+        /// Where to write the archive
+        output: String,
+
+        /// The title of the archive, csv files do not include a title.
+        /// Defaults to the file name of `input`
+        #[clap(long)]
+        title: Option<String>,
+    },
+    /// Seed an archive from the results criterion stores in `target/criterion`
+    ImportCriterion {
+        /// The criterion output directory, usually `target/criterion`
+        dir: String,
+
+        /// Where to write the archive, if it already exists the imported results are merged into it
+        output: String,
+
+        /// The machine type to record the results under, e.g. `x86_64-linux-desktop`
+        #[clap(long)]
+        machine_type: String,
+
+        /// Which criterion baseline to import
+        #[clap(long, default_value = "new")]
+        baseline: String,
+
+        /// The title of the archive, must match `output` if it already exists
+        #[clap(long, default_value = "Benches")]
+        title: String,
+    },
+    /// Pin the latest measurement of every series in an archive as a named baseline, e.g. `v1.0`.
+    /// Later runs can be compared against it with `--compare-baseline` or in the viewer.
+    PinBaseline {
+        /// The archive to modify, usually a `bench_history.cbor`
+        archive: String,
+
+        /// Replaces any existing baseline of the same name
+        name: String,
+    },
+    /// Remove a named baseline from an archive
+    RemoveBaseline {
+        /// The archive to modify, usually a `bench_history.cbor`
+        archive: String,
+
+        name: String,
+    },
 }
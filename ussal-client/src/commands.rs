@@ -0,0 +1,135 @@
+//! Subcommands for working with archives that do not run any benches.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use ussal_archive::BenchArchive;
+
+/// Nothing is written if any of the archives conflict.
+pub fn merge(inputs: &[String], output: &str) -> Result<()> {
+    let mut paths: Vec<&str> = inputs.iter().map(|x| x.as_str()).collect();
+    let output_exists = Path::new(output).exists();
+    if output_exists {
+        paths.insert(0, output);
+    }
+
+    let mut merged = load(paths[0])?;
+    for path in &paths[1..] {
+        merged
+            .merge(load(path)?)
+            .map_err(|e| anyhow!(e).context(format!("Failed to merge {path:?}")))?;
+    }
+    if output_exists {
+        save_keeping_encoding(&merged, output)
+    } else {
+        save(&merged, output)
+    }
+}
+
+pub fn export(input: &str, output: &str) -> Result<()> {
+    let archive = load(input)?;
+    let text = match extension(output)? {
+        Format::Json => archive.to_json()?,
+        Format::Csv => archive.to_csv(),
+    };
+    std::fs::write(output, text)
+        .map_err(|e| anyhow!(e).context(format!("Failed to write {output:?}")))
+}
+
+pub fn import(input: &str, output: &str, title: Option<&str>) -> Result<()> {
+    let text = std::fs::read_to_string(input)
+        .map_err(|e| anyhow!(e).context(format!("Failed to read {input:?}")))?;
+    let archive = match extension(input)? {
+        Format::Json => {
+            let mut archive = BenchArchive::from_json(&text)
+                .map_err(|e| anyhow!(e).context(format!("Failed to import {input:?}")))?;
+            if let Some(title) = title {
+                archive.title = title.to_owned();
+            }
+            archive
+        }
+        Format::Csv => {
+            let title = title.map(|x| x.to_owned()).unwrap_or_else(|| {
+                Path::new(input)
+                    .file_stem()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+            BenchArchive::from_csv(title, &text)
+                .map_err(|e| anyhow!(e).context(format!("Failed to import {input:?}")))?
+        }
+    };
+    save(&archive, output)
+}
+
+pub fn import_criterion(
+    dir: &str,
+    output: &str,
+    machine_type: &str,
+    baseline: &str,
+    title: &str,
+) -> Result<()> {
+    let imported =
+        BenchArchive::from_criterion_dir(title.to_owned(), Path::new(dir), baseline, machine_type)?;
+    let archive = if Path::new(output).exists() {
+        let mut archive = load(output)?;
+        archive
+            .merge(imported)
+            .map_err(|e| anyhow!(e).context(format!("Failed to merge into {output:?}")))?;
+        archive
+    } else {
+        imported
+    };
+    save(&archive, output)
+}
+
+pub fn pin_baseline(path: &str, name: &str) -> Result<()> {
+    let mut archive = load(path)?;
+    archive.pin_baseline(name, crate::metadata::now());
+    save_keeping_encoding(&archive, path)
+}
+
+pub fn remove_baseline(path: &str, name: &str) -> Result<()> {
+    let mut archive = load(path)?;
+    if !archive.remove_baseline(name) {
+        return Err(anyhow!("{path:?} has no baseline named {name:?}"));
+    }
+    save_keeping_encoding(&archive, path)
+}
+
+fn load(path: &str) -> Result<BenchArchive> {
+    BenchArchive::load(path).map_err(|e| anyhow!(e).context(format!("Failed to load {path:?}")))
+}
+
+fn save(archive: &BenchArchive, path: &str) -> Result<()> {
+    archive
+        .save(path)
+        .map_err(|e| anyhow!(e).context(format!("Failed to save {path:?}")))
+}
+
+/// Histories are saved compact for the viewer, so modifying one should not undo that.
+fn save_keeping_encoding(archive: &BenchArchive, path: &str) -> Result<()> {
+    let bytes =
+        std::fs::read(path).map_err(|e| anyhow!(e).context(format!("Failed to read {path:?}")))?;
+    if BenchArchive::is_compact(&bytes) {
+        archive
+            .save_compact(path)
+            .map_err(|e| anyhow!(e).context(format!("Failed to save {path:?}")))
+    } else {
+        save(archive, path)
+    }
+}
+
+enum Format {
+    Json,
+    Csv,
+}
+
+fn extension(path: &str) -> Result<Format> {
+    match Path::new(path).extension().and_then(|x| x.to_str()) {
+        Some("json") => Ok(Format::Json),
+        Some("csv") => Ok(Format::Csv),
+        _ => Err(anyhow!(
+            "{path:?} must end in .json or .csv to choose a format"
+        )),
+    }
+}
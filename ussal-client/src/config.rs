@@ -2,6 +2,8 @@ use crate::cli::Args;
 use anyhow::{anyhow, Result};
 use serde::Deserialize;
 use std::path::PathBuf;
+use ussal_archive::rename::Rename;
+use ussal_archive::retention::RetentionPolicy;
 
 // TODO: one day this should become kdl and these comments should go in the example config
 #[derive(Deserialize, Debug)]
@@ -14,6 +16,19 @@ pub struct Config {
     /// DANGER: Increment this number to force the CI bench history to reset.
     /// Provided in this strange form to allow resetting state stored in github pages.
     pub reset_ci_history: u32,
+    /// Limits how much history is kept by `--ci`, by default all history is kept
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    /// Carries the history of renamed benches over to their new name or keys
+    #[serde(default)]
+    pub renames: Vec<Rename>,
+    /// Benches missing from this many consecutive `--ci` runs are marked as retired and hidden by the viewer by default
+    #[serde(default = "default_retire_after_runs")]
+    pub retire_after_runs: u32,
+}
+
+fn default_retire_after_runs() -> u32 {
+    10
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,9 +1,56 @@
 use log::error;
+use std::fmt::Write;
 use subprocess::{Exec, Redirection};
 
-pub fn generate_web() {
-    std::fs::create_dir_all("bench_ci_web_root").unwrap();
-    std::fs::write("bench_ci_web_root/index.html", include_bytes!("index.html")).unwrap();
+/// Layout:
+/// * `bench_ci_web_root/index.html` lists every branch
+/// * `bench_ci_web_root/ussal-viewer-web*` the viewer, shared by all branches
+/// * `bench_ci_web_root/$branch/index.html` displays the history of a single branch
+/// * `bench_ci_web_root/$branch/bench_history.cbor` the history of a single branch, in the compact encoding
+pub const WEB_ROOT: &str = "bench_ci_web_root";
+
+/// Converts a branch or PR name into a name usable as a single directory, e.g. `feature/foo` becomes `feature-foo`
+/// A name with nothing usable, e.g. `..`, becomes `_` rather than empty, which would put its history in the web root.
+pub fn branch_dir(branch: &str) -> String {
+    let dir = branch
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_owned();
+    if dir.is_empty() {
+        "_".to_owned()
+    } else {
+        dir
+    }
+}
+
+pub fn history_path(branch_dir: &str) -> String {
+    format!("{WEB_ROOT}/{branch_dir}/bench_history.cbor")
+}
+
+/// Generates the viewer for `branch_dir`.
+/// Branches other than the main branch overlay their results on the main branch history.
+pub fn generate_web(branch_dir: &str, main_branch_dir: &str) {
+    std::fs::create_dir_all(format!("{WEB_ROOT}/{branch_dir}")).unwrap();
+    let baseline = if branch_dir == main_branch_dir {
+        String::new()
+    } else {
+        format!(
+            r#"<meta name="ussal-baseline" content="../{main_branch_dir}/bench_history.cbor" />"#
+        )
+    };
+    std::fs::write(
+        format!("{WEB_ROOT}/{branch_dir}/index.html"),
+        include_str!("index.html").replace("<!-- ussal-baseline -->", &baseline),
+    )
+    .unwrap();
 
     // TODO: in production builds we should fetch the wasm from a github release instead of building locally.
     //       It will be: way faster, less non-cargo dependencies and avoid wasm-bindgen version mismatches.
@@ -35,16 +82,45 @@ pub fn generate_web() {
     }
     std::fs::copy(
         "ussal-viewer-web/target/generated/ussal-viewer-web_bg.wasm",
-        "bench_ci_web_root/ussal-viewer-web_bg.wasm",
+        format!("{WEB_ROOT}/ussal-viewer-web_bg.wasm"),
     )
     .unwrap();
     std::fs::copy(
         "ussal-viewer-web/target/generated/ussal-viewer-web.js",
-        "bench_ci_web_root/ussal-viewer-web.js",
+        format!("{WEB_ROOT}/ussal-viewer-web.js"),
+    )
+    .unwrap();
+}
+
+/// Writes an index page linking to every branch that has a history, with the main branch first.
+pub fn generate_index(title: &str, main_branch_dir: &str) {
+    let mut branches: Vec<String> = std::fs::read_dir(WEB_ROOT)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("bench_history.cbor").exists())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    branches.sort_by_key(|branch| (branch != main_branch_dir, branch.clone()));
+
+    let mut links = String::new();
+    for branch in &branches {
+        writeln!(links, "        <li><a href=\"{branch}/\">{branch}</a></li>").unwrap();
+    }
+    std::fs::write(
+        format!("{WEB_ROOT}/index.html"),
+        include_str!("branches.html")
+            .replace("<!-- ussal-title -->", &escape_html(title))
+            .replace("<!-- ussal-branches -->\n", &links),
     )
     .unwrap();
 }
 
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 fn run_command_in_dir(command: &str, args: &[&str], dir: &str) {
     let data = Exec::cmd(command)
         .args(args)
@@ -64,3 +140,18 @@ fn run_command_in_dir(command: &str, args: &[&str], dir: &str) {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branch_dir_is_a_single_directory() {
+        assert_eq!(branch_dir("main"), "main");
+        assert_eq!(branch_dir("feature/x"), "feature-x");
+        assert_eq!(branch_dir("../../etc"), "-..-etc");
+        assert_eq!(branch_dir(".hidden"), "hidden");
+        assert_eq!(branch_dir(".."), "_");
+        assert_eq!(branch_dir(""), "_");
+    }
+}
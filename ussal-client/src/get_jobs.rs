@@ -1,13 +1,26 @@
 use crate::{cli::Args, config::Config};
 use anyhow::{anyhow, Result};
 use cargo_metadata::{Message, MetadataCommand};
+use std::collections::HashMap;
 use std::process::{Command, Stdio};
+use ussal_networking::binary_hash::BinaryHash;
 use ussal_networking::orchestrator_protocol::JobRequest;
 use uuid::Uuid;
 
-pub fn get_jobs(args: &Args, config: &Config) -> Result<Vec<JobRequest>> {
+pub struct Jobs {
+    pub auth_token: Uuid,
+    /// Every binary referred to by `requests`, each uploaded once regardless of how many requests refer to it
+    pub binaries: HashMap<BinaryHash, Vec<u8>>,
+    pub requests: Vec<JobRequest>,
+}
+
+pub fn get_jobs(args: &Args, config: &Config) -> Result<Jobs> {
+    let auth_token = args
+        .auth_token
+        .ok_or_else(|| anyhow!("--auth-token is required to run benches"))?;
     // Run the command to stdout once so the user can see it.
     let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut binaries = HashMap::new();
     let mut jobs = vec![];
 
     for run in &config.runs {
@@ -42,10 +55,13 @@ pub fn get_jobs(args: &Args, config: &Config) -> Result<Vec<JobRequest>> {
             if let Message::CompilerArtifact(artifact) = message.unwrap() {
                 if artifact.target.is_bench() {
                     if let Some(binary) = artifact.executable {
+                        let binary = std::fs::read(binary)?;
+                        let binary_hash = BinaryHash::of(&binary);
+                        binaries.insert(binary_hash, binary);
                         jobs.push(JobRequest {
-                            auth_token: args.auth_token,
+                            auth_token,
                             job_id: Uuid::new_v4(),
-                            binary: std::fs::read(binary)?,
+                            binary_hash,
                             machine_type: run.machine_type.clone(),
                         })
                     }
@@ -63,5 +79,9 @@ pub fn get_jobs(args: &Args, config: &Config) -> Result<Vec<JobRequest>> {
         }
     }
 
-    Ok(jobs)
+    Ok(Jobs {
+        auth_token,
+        binaries,
+        requests: jobs,
+    })
 }
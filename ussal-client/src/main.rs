@@ -2,50 +2,46 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod cli;
+mod commands;
 mod config;
 mod gen_web;
 mod get_jobs;
+mod metadata;
 mod run_jobs;
 
 use clap::Parser;
-use cli::Args;
-use ussal_archive::{Bench, BenchArchive, BenchMeasurement};
+use cli::{Args, Command};
+use std::io::ErrorKind;
+use ussal_archive::compare::{self, Verdict};
+use ussal_archive::query::Query;
+use ussal_archive::regression::{DetectionConfig, FindingKind, SeriesFinding};
+use ussal_archive::report;
+use ussal_archive::{ArchiveError, Bench, BenchArchive, BenchMeasurement, RunMetadata};
 
 /// `cargo bench`
 /// flags:
 /// --ci
 ///     1. CI infrastructure runs: `git fetch origin/gh-pages; git checkout origin/gh-pages -- bench_ci_web_root`
-///     2. CI infrastructure runs: `cargo bench --ci` which will:
-///         1. read existing state from bench_ci_web_root/bench_history.cbor
-///         2. insert new run to state
-///         3. write new state to bench_ci_web_root/bench_history.cbor
-///         4. Generates viewer html + wasm into `bench_ci_web_root/`
-///     3. CI infrastructure then needs to:
-///        on main branch checkin: copy bench_ci_web_root to gh-pages/bench_ci_web_root
-///        on PR branch received: copy bench_ci_web_root to gh-pages/repo_name/branch_name
+///     2. CI infrastructure runs: `cargo bench --ci --branch $branch` which will:
+///         1. read existing state from bench_ci_web_root/$branch/bench_history.cbor
+///         2. compare the new run against bench_ci_web_root/main/bench_history.cbor
+///         3. insert new run to state
+///         4. write new state to bench_ci_web_root/$branch/bench_history.cbor
+///         5. Generates viewer html + wasm into `bench_ci_web_root/` and an index page listing every branch
+///     3. CI infrastructure then needs to copy bench_ci_web_root to gh-pages/bench_ci_web_root
 ///     
 /// --file-name
 ///     - write output to specified filename
 /// by default overwrites bench.cbor in cwd
 ///
 ///
-/// `cargo benchcompare`
-/// either an xtask or an always backwards compatible `cargo-benchcompare`.
-/// I have to be backwards compatible anyway for CI purposes, so may as well just do a `cargo-benchcompare`
-/// usage: `cargo benchcompare $file1.cbor $file2.cbor`
+/// `cargo benchcompare $file1.cbor $file2.cbor`
+/// provided by the always backwards compatible `cargo-benchcompare` crate.
 /// compares changes between $file1.cbor and $file2.cbor
-
-/// TODO:
-/// current thoughts:
-/// combine --ci and --file-name:
-/// output format is combinable with other outputs.
-/// output format embeds OS string
-///
-/// This way we can parallelize the workload across multiple workflows
 ///
-/// If we have a combiner workflow copied in by default it should be easier to introduce a new parallel workflow
-///
-/// need to think through this approach.
+/// `ussal-client merge $file.cbor... --output $history.cbor`
+/// combines the outputs of parallel workflows, e.g. one per OS, into a single history.
+/// Series measured on different machines stay separate as each is keyed by its `machine`.
 
 #[tokio::main]
 async fn main() {
@@ -58,6 +54,34 @@ async fn run() -> i32 {
 
     let args = Args::parse();
 
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Merge { inputs, output } => commands::merge(inputs, output),
+            Command::Export { input, output } => commands::export(input, output),
+            Command::Import {
+                input,
+                output,
+                title,
+            } => commands::import(input, output, title.as_deref()),
+            Command::ImportCriterion {
+                dir,
+                output,
+                machine_type,
+                baseline,
+                title,
+            } => commands::import_criterion(dir, output, machine_type, baseline, title),
+            Command::PinBaseline { archive, name } => commands::pin_baseline(archive, name),
+            Command::RemoveBaseline { archive, name } => commands::remove_baseline(archive, name),
+        };
+        return match result {
+            Ok(()) => 0,
+            Err(err) => {
+                tracing::error!("{err:?}");
+                1
+            }
+        };
+    }
+
     let config = match config::Config::load(&args) {
         Ok(config) => config,
         Err(err) => {
@@ -81,45 +105,233 @@ async fn run() -> i32 {
         }
     };
 
-    let benches: Vec<Bench> = job_results
-        .flat_map(|job| {
-            let machine_type = job.machine_type;
-            job.benches.into_iter().map(move |bench| Bench {
-                name: bench.bench_name,
-                keys: {
-                    let mut keys = bench.keys;
-                    keys.insert("machine".to_owned(), machine_type.clone());
-                    keys
-                },
-                measurements: vec![BenchMeasurement {
-                    value: bench.wall_time,
-                }],
-            })
-        })
-        .collect();
-
-    let results = BenchArchive::new(config.title.to_owned(), benches);
+    let run_metadata = metadata::run_metadata();
+
+    // Each metric is stored as a separate series, distinguished by the `type` key.
+    let mut benches: Vec<Bench> = vec![];
+    for job in job_results {
+        for bench in job.benches {
+            let metadata = RunMetadata {
+                runner: Some(bench.runner_fingerprint),
+                ..run_metadata.clone()
+            };
+            for metric in bench.metrics {
+                let mut keys = bench.keys.clone();
+                keys.insert("machine".to_owned(), job.machine_type.clone());
+                keys.insert("type".to_owned(), metric.type_key());
+                let measurement = match metric.samples {
+                    Some(samples) => BenchMeasurement::with_samples(
+                        metric.value as f32,
+                        samples.into_iter().map(|x| x as f32).collect(),
+                    ),
+                    None => BenchMeasurement::new(metric.value as f32),
+                };
+                benches.push(Bench::new(
+                    bench.bench_name.clone(),
+                    keys,
+                    vec![measurement.with_metadata(metadata.clone())],
+                ));
+            }
+        }
+    }
+
+    let mut results =
+        BenchArchive::new(config.title.to_owned(), benches).with_reset_id(config.reset_ci_history);
+
+    if let Some(path) = &args.markdown_report {
+        write_markdown_report(&args, &config, path, &results);
+    }
 
     if args.ci {
+        let branch = gen_web::branch_dir(
+            args.branch
+                .as_deref()
+                .or(run_metadata.branch.as_deref())
+                .unwrap_or(&args.main_branch),
+        );
+        let main_branch = gen_web::branch_dir(&args.main_branch);
         // TODO: handle unwraps
-        gen_web::generate_web();
-        let history = match BenchArchive::load("bench_ci_web_root/bench_history.cbor") {
+        gen_web::generate_web(&branch, &main_branch);
+        let mut history = match load_history(&branch, &main_branch) {
             Ok(mut history) => {
-                history.title = config.title;
+                history.title = config.title.clone();
                 history.reset_if_mismatch(config.reset_ci_history);
-                history.insert(results);
+                rename(&mut history, &config);
                 history
             }
+            Err(ArchiveError::Io { error, .. }) if error.kind() == ErrorKind::NotFound => {
+                tracing::info!("No existing history, history is starting from scratch");
+                BenchArchive::new(config.title.clone(), vec![])
+                    .with_reset_id(config.reset_ci_history)
+            }
+            // Starting from scratch would overwrite the history, so leave it for a human to recover.
             Err(err) => {
-                // TODO: file not existing should not be a warn
-                tracing::warn!("Failed to load history, history is starting from scratch: {err:?}");
-                results
+                tracing::error!("Failed to load history: {err}");
+                return 1;
             }
         };
-        history.save("bench_ci_web_root/bench_history.cbor");
-    } else {
-        results.save("bench.cbor");
+
+        // Other branches are compared against the main branch, as it has far more history than the branch itself.
+        let main_history = if branch == main_branch {
+            None
+        } else {
+            match BenchArchive::load(&gen_web::history_path(&main_branch)) {
+                Ok(mut main_history) => {
+                    rename(&mut main_history, &config);
+                    Some(main_history)
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to load the main branch history, comparing against this branch's own history instead: {err}");
+                    None
+                }
+            }
+        };
+        let findings = main_history
+            .as_ref()
+            .unwrap_or(&history)
+            .detect_changes(&mut results, &DetectionConfig::default());
+        let baseline_summary = args
+            .compare_baseline
+            .as_deref()
+            .map(|name| compare_to_baseline(&history, name, &results));
+        write_summary(
+            &format!("{}/{branch}/summary.txt", gen_web::WEB_ROOT),
+            &findings,
+            baseline_summary.as_deref(),
+        );
+
+        history.insert(results);
+        history.retire(config.retire_after_runs);
+        if let Some(name) = &args.pin_baseline {
+            tracing::info!("Pinning this run as the baseline {name:?}");
+            history.pin_baseline(name, metadata::now());
+        }
+        if let Some(retention) = &config.retention {
+            history.compact(retention, metadata::now());
+        }
+        let history_path = gen_web::history_path(&branch);
+        if let Err(err) = history.save_compact(&history_path) {
+            tracing::error!("Failed to save history: {err}");
+            return 1;
+        }
+        warn_if_large(&history_path);
+        gen_web::generate_index(&config.title, &main_branch);
+    } else if let Err(err) = results.save("bench.cbor") {
+        tracing::error!("Failed to save results: {err}");
+        return 1;
     }
 
     0
 }
+
+fn rename(history: &mut BenchArchive, config: &config::Config) {
+    let renamed = history.rename(&config.renames);
+    if renamed > 0 {
+        tracing::info!("Renamed {renamed} series in the history");
+    }
+}
+
+fn load_history(branch: &str, main_branch: &str) -> Result<BenchArchive, ArchiveError> {
+    let path = gen_web::history_path(branch);
+    // Before histories were stored per branch, the main branch history was stored directly in the web root.
+    let legacy_path = format!("{}/bench_history.cbor", gen_web::WEB_ROOT);
+    if branch == main_branch
+        && !std::path::Path::new(&path).exists()
+        && std::path::Path::new(&legacy_path).exists()
+    {
+        tracing::info!(
+            "Loading history from {legacy_path:?}, it will be saved to {path:?} from now on"
+        );
+        return BenchArchive::load(&legacy_path);
+    }
+    BenchArchive::load(&path)
+}
+
+/// Large histories are slow for the viewer to fetch and GitHub Pages rejects files over 100MB
+fn warn_if_large(path: &str) {
+    const WARN_SIZE: u64 = 20 * 1024 * 1024;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.len() > WARN_SIZE {
+            tracing::warn!(
+                "{path:?} is {}MB, configure a `retention` policy to bound its size",
+                metadata.len() / 1024 / 1024
+            );
+        }
+    }
+}
+
+/// Logs how `results` changed since the baseline `name` was pinned in `history`, and returns the same as text for the summary.
+fn compare_to_baseline(history: &BenchArchive, name: &str, results: &BenchArchive) -> String {
+    let Some(baseline) = history.baseline_archive(name) else {
+        tracing::warn!("The history has no baseline named {name:?} to compare against");
+        return format!("No baseline named {name:?}\n");
+    };
+    let mut summary = format!("Compared to baseline {name:?}:\n");
+    for comparison in compare::compare(&baseline, results, &Query::default()) {
+        let change = match comparison.change() {
+            Some(change) => format!("{:+.2}%", change * 100.0),
+            None => "-".to_owned(),
+        };
+        let verdict = comparison.verdict(DetectionConfig::default().threshold);
+        let line = format!(
+            "{} {}: {change} {verdict:?}",
+            comparison.name,
+            comparison.keys_string()
+        );
+        match verdict {
+            Verdict::Regressed => tracing::warn!("{line} since baseline {name:?}"),
+            _ => tracing::info!("{line} since baseline {name:?}"),
+        }
+        summary.push_str(&format!("{line}\n"));
+    }
+    summary
+}
+
+/// Logs the findings and writes them to `path`, one per line, so CI can surface them.
+/// `baseline_summary` is appended after the findings.
+fn write_summary(path: &str, findings: &[SeriesFinding], baseline_summary: Option<&str>) {
+    let mut summary = String::new();
+    for finding in findings {
+        match finding.finding.kind {
+            FindingKind::Regression => tracing::warn!("{finding}"),
+            FindingKind::Improvement => tracing::info!("{finding}"),
+        }
+        summary.push_str(&format!("{finding}\n"));
+    }
+    if findings.is_empty() {
+        tracing::info!("No significant changes detected");
+        summary.push_str("No significant changes detected\n");
+    }
+    if let Some(baseline_summary) = baseline_summary {
+        summary.push_str(baseline_summary);
+    }
+    if let Err(err) = std::fs::write(path, summary) {
+        tracing::error!("Failed to write summary to {path:?}: {err}");
+    }
+}
+
+fn write_markdown_report(args: &Args, config: &config::Config, path: &str, results: &BenchArchive) {
+    let history_path = match &args.baseline_history {
+        Some(path) => path.clone(),
+        None => gen_web::history_path(&gen_web::branch_dir(&args.main_branch)),
+    };
+    let history = match BenchArchive::load(&history_path) {
+        Ok(mut history) => {
+            rename(&mut history, config);
+            history
+        }
+        Err(err) => {
+            tracing::warn!("Failed to load baseline history, the report will have nothing to compare against: {err:?}");
+            BenchArchive::new(results.title.clone(), vec![])
+        }
+    };
+    let markdown = report::markdown(
+        &history,
+        results,
+        &DetectionConfig::default(),
+        args.viewer_url.as_deref(),
+    );
+    if let Err(err) = std::fs::write(path, markdown) {
+        tracing::error!("Failed to write markdown report to {path:?}: {err}");
+    }
+}
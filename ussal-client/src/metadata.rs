@@ -0,0 +1,51 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ussal_archive::RunMetadata;
+
+/// Collects metadata describing the current run from git and the local toolchain.
+/// Anything that cannot be determined is left as `None`.
+pub fn run_metadata() -> RunMetadata {
+    RunMetadata {
+        commit: command_output("git", &["rev-parse", "HEAD"]),
+        branch: branch(),
+        commit_time: command_output("git", &["show", "-s", "--format=%ct", "HEAD"])
+            .and_then(|time| time.parse().ok()),
+        run_time: Some(now()),
+        toolchain: command_output(
+            &std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned()),
+            &["--version"],
+        ),
+        // Filled in per measurement from the runner that took it.
+        runner: None,
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn branch() -> Option<String> {
+    // CI usually checks out a detached HEAD, so prefer the branch name CI gives us.
+    // On github actions GITHUB_HEAD_REF is set for PRs and GITHUB_REF_NAME otherwise.
+    for var in ["GITHUB_HEAD_REF", "GITHUB_REF_NAME"] {
+        if let Ok(branch) = std::env::var(var) {
+            if !branch.is_empty() {
+                return Some(branch);
+            }
+        }
+    }
+    command_output("git", &["rev-parse", "--abbrev-ref", "HEAD"]).filter(|x| x != "HEAD")
+}
+
+fn command_output(command: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(command).args(args).output().ok()?;
+    if output.status.success() {
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+    } else {
+        None
+    }
+}
@@ -1,9 +1,14 @@
-use crate::{cli::Args, config::Config};
+use crate::{cli::Args, config::Config, get_jobs::Jobs};
 use anyhow::{anyhow, Result};
 use std::{collections::HashMap, time::Duration};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::time::timeout;
 use tokio_tungstenite::connect_async;
-use ussal_networking::orchestrator_protocol::{BenchComplete, JobRequest};
+use ussal_networking::hello::{Hello, Protocol};
+use ussal_networking::orchestrator_protocol::{BenchComplete, ClientMessage, OrchestratorMessage};
+use ussal_networking::transfer::{self, TransferProgress, CHUNK_SIZE};
+use ussal_networking::ReceiveError;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct JobResult {
@@ -15,24 +20,31 @@ pub struct JobResult {
 pub async fn run_jobs(
     args: &Args,
     config: &Config,
-    jobs: Vec<JobRequest>,
+    jobs: Jobs,
 ) -> Result<impl Iterator<Item = JobResult>> {
-    assert!(!jobs.is_empty(), "jobs must contain values otherwise we will deadlock waiting for a response that will never come");
+    assert!(!jobs.requests.is_empty(), "jobs must contain values otherwise we will deadlock waiting for a response that will never come");
     let mut job_results = HashMap::new();
 
     let uri = args.address.as_ref().unwrap_or(&config.address);
-    let (ws_stream, _) = timeout(Duration::from_secs(10), connect_async(uri))
+    let (mut ws_stream, _) = timeout(Duration::from_secs(10), connect_async(uri))
         .await
         .map_err(|_| anyhow!("Timed out connecting to {uri} after 10 seconds"))?
         .map_err(|e| anyhow!(e).context(format!("Failed to connect to {uri}")))?;
     tracing::info!("WebSocket handshake has been successfully completed");
-    let (tx, mut rx) = ussal_networking::spawn_read_write_tasks::<
-        ussal_networking::orchestrator_protocol::JobRequest,
-        ussal_networking::orchestrator_protocol::JobResponse,
-    >(ws_stream)
-    .await;
+    let hello = Hello::new(
+        Protocol::Orchestrator,
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
+    );
+    let negotiated = ussal_networking::send_hello(&mut ws_stream, &hello).await?;
+    tracing::info!("Using protocol version {}", negotiated.version);
+    let (tx, mut rx) =
+        ussal_networking::spawn_read_write_tasks::<ClientMessage, OrchestratorMessage>(ws_stream)
+            .await;
 
-    for job in jobs {
+    for binary in jobs.binaries.values() {
+        upload_binary(&tx, &mut rx, jobs.auth_token, binary).await?;
+    }
+    for job in jobs.requests {
         job_results.insert(
             job.job_id,
             JobResult {
@@ -41,14 +53,30 @@ pub async fn run_jobs(
                 benches: vec![],
             },
         );
-        tx.send(job).unwrap();
+        tx.send(ClientMessage::RunJob(job)).unwrap();
     }
 
     while let Some(response) = rx.recv().await {
+        let response = match response {
+            Ok(OrchestratorMessage::JobResponse(response)) => response,
+            Ok(other) => return Err(anyhow!("Expected a job response but was {other:?}")),
+            Err(ReceiveError::Closed) => break,
+            Err(err) => {
+                return Err(anyhow!(err).context("Failed to receive response from orchestrator"))
+            }
+        };
         match response.result {
             ussal_networking::orchestrator_protocol::JobResult::BenchComplete(bench) => {
                 if let Some(job) = job_results.get_mut(&response.job_id) {
                     tracing::info!("{:?}", bench);
+                    for metric in &bench.unavailable {
+                        tracing::warn!(
+                            "{} is unavailable for {:?}: {}",
+                            metric.name,
+                            bench.bench_name,
+                            metric.reason
+                        );
+                    }
                     job.benches.push(bench);
                 } else {
                     return Err(anyhow!("BenchComplete contained unknown job_id"));
@@ -68,6 +96,13 @@ pub async fn run_jobs(
             ussal_networking::orchestrator_protocol::JobResult::JobError(e) => {
                 return Err(anyhow!(e))
             }
+            ussal_networking::orchestrator_protocol::JobResult::BinaryTransfer {
+                machine_type,
+                progress,
+            } => log_progress(
+                &format!("Sending binary to {machine_type} runner"),
+                &progress,
+            ),
         }
         if job_results.values().all(|x| x.finished) {
             return Ok(job_results.into_values());
@@ -78,3 +113,42 @@ pub async fn run_jobs(
         "Connection was closed before all jobs were finished. Last known job state: {job_results:#?}"
     ))
 }
+
+/// Uploads one chunk at a time, waiting for the orchestrator to acknowledge each chunk before sending the next
+async fn upload_binary(
+    tx: &UnboundedSender<ClientMessage>,
+    rx: &mut UnboundedReceiver<Result<OrchestratorMessage, ReceiveError>>,
+    auth_token: Uuid,
+    binary: &[u8],
+) -> Result<()> {
+    for chunk in transfer::chunks(binary) {
+        tx.send(ClientMessage::UploadBinary { auth_token, chunk })
+            .unwrap();
+        match rx.recv().await {
+            Some(Ok(OrchestratorMessage::UploadProgress(progress))) => {
+                log_progress("Uploading binary to orchestrator", &progress)
+            }
+            // The reason is returned as is, e.g. `Invalid auth token`, since it is already readable
+            Some(Ok(OrchestratorMessage::UploadFailed { reason, .. })) => {
+                return Err(anyhow!(reason))
+            }
+            Some(Ok(other)) => return Err(anyhow!("Expected upload progress but was {other:?}")),
+            Some(Err(err)) => return Err(anyhow!("Failed to upload binary: {err}")),
+            None => return Err(anyhow!("Failed to upload binary: {}", ReceiveError::Closed)),
+        }
+    }
+    Ok(())
+}
+
+/// Logs roughly every 10% so that large binaries do not flood the log
+fn log_progress(description: &str, progress: &TransferProgress) {
+    let tenth = |bytes: u64| bytes * 10 / progress.total_bytes.max(1);
+    let previous = progress.received_bytes.saturating_sub(CHUNK_SIZE as u64);
+    if progress.is_complete() || tenth(previous) != tenth(progress.received_bytes) {
+        tracing::info!(
+            "{description}: {:.1}/{:.1}MB",
+            progress.received_bytes as f64 / 1_000_000.0,
+            progress.total_bytes as f64 / 1_000_000.0
+        );
+    }
+}
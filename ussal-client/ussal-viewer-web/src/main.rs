@@ -36,37 +36,80 @@ async fn run() {
         .style()
         .set_css_text("margin: 0; height: 100%; width: 100%");
 
-    let raw_cbor = get_bench_history().await;
-    let archive = BenchArchive::load_from_cbor(&raw_cbor);
+    let archive = match fetch("bench_history.cbor").await {
+        Some(raw_cbor) => BenchArchive::load_from_cbor(&raw_cbor),
+        None => {
+            show_error(&body, "Failed to fetch bench_history.cbor");
+            return;
+        }
+    };
+    let archive = match archive {
+        Ok(archive) => archive,
+        Err(err) => {
+            show_error(&body, &format!("Failed to load bench_history.cbor: {err}"));
+            return;
+        }
+    };
+    let mut app_baseline = None;
+    // Pages of branches other than the main branch specify the main branch history to overlay their results on.
+    if let Some(baseline) = document
+        .query_selector("meta[name=ussal-baseline]")
+        .unwrap()
+        .and_then(|meta| meta.get_attribute("content"))
+    {
+        match fetch(&baseline).await {
+            Some(raw_cbor) => match BenchArchive::load_from_cbor(&raw_cbor) {
+                Ok(baseline) => app_baseline = Some(baseline),
+                Err(err) => tracing::warn!("Failed to load baseline {baseline:?}: {err}"),
+            },
+            None => tracing::warn!("Failed to fetch baseline {baseline:?}"),
+        }
+    }
 
     let runner = WebRunner::new();
     runner
         .start(
             "the-id",
             eframe::WebOptions::default(),
-            Box::new(|cc| Box::new(crate::App::new(cc, archive))),
+            Box::new(|cc| {
+                let app = crate::App::new(cc, archive);
+                Box::new(match app_baseline {
+                    Some(baseline) => app.with_baseline(baseline),
+                    None => app,
+                })
+            }),
         )
         .await
         .unwrap();
 }
 
-async fn get_bench_history() -> Vec<u8> {
+/// Replaces the page with `message`, used when there is nothing that the viewer could display
+fn show_error(body: &HtmlElement, message: &str) {
+    tracing::error!("{message}");
+    body.set_inner_text(message);
+}
+
+/// Returns None if the server did not respond with a success status
+async fn fetch(path: &str) -> Option<Vec<u8>> {
     let mut opts = RequestInit::new();
     opts.method("GET");
     opts.mode(RequestMode::Cors);
 
-    let request = Request::new_with_str_and_init("bench_history.cbor", &opts).unwrap();
+    let request = Request::new_with_str_and_init(path, &opts).unwrap();
 
     let window = web_sys::window().unwrap();
     let resp_value = JsFuture::from(window.fetch_with_request(&request))
         .await
-        .unwrap();
+        .ok()?;
 
     // `resp_value` is a `Response` object.
     assert!(resp_value.is_instance_of::<Response>());
     let resp: Response = resp_value.dyn_into().unwrap();
+    if !resp.ok() {
+        return None;
+    }
 
     // Convert this other `Promise` into a rust `Future`.
     let js_value = JsFuture::from(resp.array_buffer().unwrap()).await.unwrap();
-    Uint8Array::new(&js_value).to_vec()
+    Some(Uint8Array::new(&js_value).to_vec())
 }
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Identifies a bench binary by its contents, so that a binary is only transferred to a peer that does not already have it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BinaryHash(pub [u8; 32]);
+
+impl BinaryHash {
+    /// The sha256 of `binary`
+    pub fn of(binary: &[u8]) -> Self {
+        BinaryHash(Sha256::digest(binary).into())
+    }
+}
+
+/// Formatted as lowercase hex, which is also used as the file name of cached binaries
+impl fmt::Display for BinaryHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the lowercase hex that `BinaryHash` is displayed as
+impl FromStr for BinaryHash {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let is_lower_hex = |c: u8| c.is_ascii_digit() || (b'a'..=b'f').contains(&c);
+        if text.len() != 64 || !text.bytes().all(is_lower_hex) {
+            return Err(format!("{text:?} is not a lowercase hex sha256"));
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Ok(BinaryHash(hash))
+    }
+}
+
+impl fmt::Debug for BinaryHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BinaryHash({self})")
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Why a message could not be received from the peer.
+/// A peer that sends a message that fails to decode may be malicious or running an incompatible version, so should be disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReceiveError {
+    /// The message was not valid cbor of the expected type
+    Decode { reason: String },
+    /// The message was not a binary message, e.g. a text message
+    UnexpectedMessage { kind: &'static str },
+    /// The websocket failed, usually followed by `Closed`
+    Transport { reason: String },
+    /// The peer closed the connection, no more messages will be received
+    Closed,
+    /// Nothing was received from the peer, not even a reply to a heartbeat, so it is assumed dead.
+    /// No more messages will be received.
+    TimedOut { after: Duration },
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Decode { reason } => write!(f, "Failed to decode message: {reason}"),
+            ReceiveError::UnexpectedMessage { kind } => {
+                write!(f, "Expected a binary message but received a {kind} message")
+            }
+            ReceiveError::Transport { reason } => {
+                write!(f, "Failed to receive message from websocket: {reason}")
+            }
+            ReceiveError::Closed => write!(f, "The peer closed the connection"),
+            ReceiveError::TimedOut { after } => {
+                write!(f, "The peer did not respond to heartbeats for {after:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReceiveError {}
+
+pub(crate) fn decode<T: for<'a> serde::Deserialize<'a>>(value: &[u8]) -> Result<T, ReceiveError> {
+    serde_cbor::from_slice(value).map_err(|e| ReceiveError::Decode {
+        reason: e.to_string(),
+    })
+}
@@ -0,0 +1,102 @@
+//! The first message sent over each websocket, before any `orchestrator_protocol` or `runner_protocol` messages.
+//!
+//! The connecting peer, a client or runner, sends a `Hello` stating which protocol versions and capabilities it supports.
+//! The orchestrator replies with a `HelloResponse`, either accepting with the highest version both sides support or rejecting with a readable reason.
+//! The hello itself must never change in an incompatible way, as it is how mismatched peers find out that they are mismatched.
+
+use serde::{Deserialize, Serialize};
+
+/// Bump when a change to `orchestrator_protocol` or `runner_protocol` would break peers that only know the previous version
+///
+/// * 1: binaries are embedded in every request
+/// * 2: binaries are uploaded once and referred to by `BinaryHash`
+/// * 3: binaries are sent in compressed chunks that are acknowledged with progress
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// The oldest protocol version this build can still speak
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Optional features supported by this build.
+/// Unlike protocol versions, peers ignore capabilities they do not know, so new features can be added without bumping `PROTOCOL_VERSION`.
+pub const CAPABILITIES: &[&str] = &[];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Clients submitting jobs to `/run_job`
+    Orchestrator,
+    /// Runners requesting jobs from `/request_job`
+    Runner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol: Protocol,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Vec<String>,
+    /// e.g. `ussal-client 0.1.0`, included in errors so users know what to upgrade
+    pub software: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelloResponse {
+    Accepted(Negotiated),
+    Rejected { reason: String },
+}
+
+/// What both peers agreed to use for the rest of the connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    /// The capabilities supported by both peers
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// The hello for this build, `software` is usually `concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"))`
+    pub fn new(protocol: Protocol, software: &str) -> Self {
+        Hello {
+            protocol,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|x| (*x).to_owned()).collect(),
+            software: software.to_owned(),
+        }
+    }
+
+    /// Called by the orchestrator with its own hello to decide whether to accept `peer`
+    pub fn negotiate(&self, peer: &Hello) -> Result<Negotiated, String> {
+        if peer.protocol != self.protocol {
+            return Err(format!(
+                "{} connected to the {:?} endpoint but speaks the {:?} protocol, check the address it connects to",
+                peer.software, self.protocol, peer.protocol
+            ));
+        }
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            let upgrade = if peer.max_version < self.min_version {
+                &peer.software
+            } else {
+                &self.software
+            };
+            return Err(format!(
+                "{} supports protocol versions {}..={} but {} supports {}..={}, upgrade {upgrade}",
+                peer.software,
+                peer.min_version,
+                peer.max_version,
+                self.software,
+                self.min_version,
+                self.max_version,
+            ));
+        }
+        Ok(Negotiated {
+            version,
+            capabilities: self
+                .capabilities
+                .iter()
+                .filter(|x| peer.capabilities.contains(x))
+                .cloned()
+                .collect(),
+        })
+    }
+}
@@ -1,26 +1,132 @@
+pub mod binary_hash;
+mod error;
+pub mod hello;
+pub mod metric;
 pub mod orchestrator_protocol;
 pub mod runner_protocol;
+pub mod transfer;
 
+use anyhow::{anyhow, Result};
+pub use error::ReceiveError;
 use futures_util::{SinkExt, StreamExt};
+use hello::{Hello, HelloResponse, Negotiated};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::{timeout, MissedTickBehavior};
 use tokio::{net::TcpStream, sync::mpsc};
 use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
+/// How long to wait for the other side of the hello before giving up on the connection
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to ping the peer, which replies with a pong automatically, and how long it may stay silent before it is assumed dead
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    /// The timeout is several intervals long so that a single delayed pong on a slow link does not drop the connection
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sends `hello` to the orchestrator and waits for it to be accepted.
+/// Must be called before `spawn_read_write_tasks`.
+pub async fn send_hello(
+    socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    hello: &Hello,
+) -> Result<Negotiated> {
+    socket
+        .send(Message::Binary(serde_cbor::to_vec(hello)?))
+        .await
+        .map_err(|e| anyhow!(e).context("Failed to send hello"))?;
+
+    let receive = async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Binary(value))) => return Ok(value),
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(anyhow!(
+                        "The orchestrator closed the connection without replying to the hello"
+                    ))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    return Err(anyhow!(err).context("Failed to receive the reply to the hello"))
+                }
+            }
+        }
+    };
+    let reply = tokio::time::timeout(HELLO_TIMEOUT, receive)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The orchestrator did not reply to the hello within {HELLO_TIMEOUT:?}, it is likely running a version of ussal from before protocol versioning and needs to be upgraded"
+            )
+        })??;
+    match serde_cbor::from_slice(&reply) {
+        Ok(HelloResponse::Accepted(negotiated)) => Ok(negotiated),
+        Ok(HelloResponse::Rejected { reason }) => Err(anyhow!(
+            "The orchestrator rejected the connection: {reason}"
+        )),
+        Err(err) => Err(anyhow!(err).context("Failed to decode the reply to the hello")),
+    }
+}
+
+/// Messages sent to the returned sender are written to the socket, and messages read from the socket are sent to the returned receiver.
+/// A message that fails to decode is received as an error rather than ending the connection, so the caller decides whether to disconnect.
+/// The peer is pinged with the default `Heartbeat`, if nothing is received from it within the timeout it is assumed dead.
+/// The last message received is always `Err(ReceiveError::Closed)` or `Err(ReceiveError::TimedOut)`, dropping the receiver closes the connection.
 pub async fn spawn_read_write_tasks<
     TS: Serialize + Send + Sync + 'static,
     TR: for<'a> Deserialize<'a> + std::fmt::Debug + Send + Sync + 'static,
 >(
     socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-) -> (mpsc::UnboundedSender<TS>, mpsc::UnboundedReceiver<TR>) {
+) -> (
+    mpsc::UnboundedSender<TS>,
+    mpsc::UnboundedReceiver<Result<TR, ReceiveError>>,
+) {
+    spawn_read_write_tasks_with_heartbeat(socket, Heartbeat::default()).await
+}
+
+/// The same as `spawn_read_write_tasks` but with a custom `heartbeat`
+pub async fn spawn_read_write_tasks_with_heartbeat<
+    TS: Serialize + Send + Sync + 'static,
+    TR: for<'a> Deserialize<'a> + std::fmt::Debug + Send + Sync + 'static,
+>(
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    heartbeat: Heartbeat,
+) -> (
+    mpsc::UnboundedSender<TS>,
+    mpsc::UnboundedReceiver<Result<TR, ReceiveError>>,
+) {
     let (mut tx, mut rx) = socket.split();
     let (request_tx, mut request_rx) = mpsc::unbounded_channel();
     let (response_tx, response_rx) = mpsc::unbounded_channel();
     tokio::spawn(async move {
-        while let Some(value) = request_rx.recv().await {
-            if let Err(err) = tx
-                .send(Message::Binary(serde_cbor::to_vec(&value).unwrap()))
-                .await
-            {
+        let mut ping = tokio::time::interval(heartbeat.interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let message = tokio::select!(
+                value = request_rx.recv() => match value {
+                    Some(value) => match serde_cbor::to_vec(&value) {
+                        Ok(bytes) => Message::Binary(bytes),
+                        Err(err) => {
+                            tracing::error!("Failed to encode message: {err}");
+                            return;
+                        }
+                    },
+                    None => return,
+                },
+                _ = ping.tick() => Message::Ping(vec![]),
+            );
+            if let Err(err) = tx.send(message).await {
                 tracing::error!("Failed to send to websocket: {err}");
                 return;
             }
@@ -29,15 +135,24 @@ pub async fn spawn_read_write_tasks<
     tokio::spawn(async move {
         loop {
             tokio::select!(
-                 value = rx.next() => {
-                    match value {
-                        Some(Ok(Message::Binary(value))) => {
-                            let a = serde_cbor::from_slice(&value).unwrap();
-                            response_tx.send(a).unwrap();
+                value = timeout(heartbeat.timeout, rx.next()) => {
+                    let Ok(value) = value else {
+                        response_tx.send(Err(ReceiveError::TimedOut { after: heartbeat.timeout })).ok();
+                        return;
+                    };
+                    let received = match value {
+                        Some(Ok(Message::Binary(value))) => error::decode(&value),
+                        Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                        Some(Ok(Message::Close(_))) | None => {
+                            response_tx.send(Err(ReceiveError::Closed)).ok();
+                            return;
                         }
-                        Some(Ok(other)) => tracing::error!("Unexpected message {other:?}"),
-                        Some(Err(err)) => tracing::error!("Failed to receive message from websocket {err:?}"),
-                        None => return,
+                        Some(Ok(Message::Text(_))) => Err(ReceiveError::UnexpectedMessage { kind: "text" }),
+                        Some(Ok(Message::Frame(_))) => Err(ReceiveError::UnexpectedMessage { kind: "raw frame" }),
+                        Some(Err(err)) => Err(ReceiveError::Transport { reason: err.to_string() }),
+                    };
+                    if response_tx.send(received).is_err() {
+                        return;
                     }
                 }
                 _ = response_tx.closed() => {
@@ -52,26 +167,99 @@ pub async fn spawn_read_write_tasks<
 
 // TODO: axum should expose way to get tungstenite types so we can avoid this duplication
 pub mod axum {
+    use crate::hello::{Hello, HelloResponse, Negotiated};
+    use crate::{error, Heartbeat, ReceiveError, HELLO_TIMEOUT};
+    use anyhow::{anyhow, Result};
     use axum::extract::ws::{Message, WebSocket};
     use futures_util::{SinkExt, StreamExt};
     use serde::{Deserialize, Serialize};
     use tokio::sync::mpsc;
+    use tokio::time::{timeout, MissedTickBehavior};
+
+    /// Waits for the peer's hello and accepts it if it is compatible with `ours`, otherwise the peer is sent the reason it was rejected.
+    /// Must be called before `spawn_read_write_tasks`.
+    pub async fn accept_hello(socket: &mut WebSocket, ours: &Hello) -> Result<Negotiated> {
+        let receive = async {
+            loop {
+                match socket.recv().await {
+                    Some(Ok(Message::Binary(value))) => return Ok(value),
+                    Some(Ok(Message::Close(_))) | None => {
+                        return Err(anyhow!("The peer disconnected before sending a hello"))
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(anyhow!(err).context("Failed to receive hello")),
+                }
+            }
+        };
+        let value = tokio::time::timeout(HELLO_TIMEOUT, receive)
+            .await
+            .map_err(|_| anyhow!("The peer did not send a hello within {HELLO_TIMEOUT:?}"))??;
 
+        let negotiated = match serde_cbor::from_slice::<Hello>(&value) {
+            Ok(peer) => ours.negotiate(&peer),
+            Err(_) => Err(format!(
+                "Expected a hello but received something else, the peer is likely running a version of ussal from before protocol versioning, upgrade it to {}",
+                ours.software
+            )),
+        };
+        let response = match &negotiated {
+            Ok(negotiated) => HelloResponse::Accepted(negotiated.clone()),
+            Err(reason) => HelloResponse::Rejected {
+                reason: reason.clone(),
+            },
+        };
+        socket
+            .send(Message::Binary(serde_cbor::to_vec(&response)?))
+            .await
+            .map_err(|e| anyhow!(e).context("Failed to reply to hello"))?;
+        negotiated.map_err(|reason| anyhow!(reason))
+    }
+
+    /// The same as `crate::spawn_read_write_tasks` but for axum websockets
     pub async fn spawn_read_write_tasks<
         TS: Serialize + Send + Sync + 'static,
         TR: for<'a> Deserialize<'a> + std::fmt::Debug + Send + Sync + 'static,
     >(
         socket: WebSocket,
-    ) -> (mpsc::UnboundedSender<TS>, mpsc::UnboundedReceiver<TR>) {
+    ) -> (
+        mpsc::UnboundedSender<TS>,
+        mpsc::UnboundedReceiver<Result<TR, ReceiveError>>,
+    ) {
+        spawn_read_write_tasks_with_heartbeat(socket, Heartbeat::default()).await
+    }
+
+    /// The same as `crate::spawn_read_write_tasks_with_heartbeat` but for axum websockets
+    pub async fn spawn_read_write_tasks_with_heartbeat<
+        TS: Serialize + Send + Sync + 'static,
+        TR: for<'a> Deserialize<'a> + std::fmt::Debug + Send + Sync + 'static,
+    >(
+        socket: WebSocket,
+        heartbeat: Heartbeat,
+    ) -> (
+        mpsc::UnboundedSender<TS>,
+        mpsc::UnboundedReceiver<Result<TR, ReceiveError>>,
+    ) {
         let (mut tx, mut rx) = socket.split();
         let (request_tx, mut request_rx) = mpsc::unbounded_channel();
         let (response_tx, response_rx) = mpsc::unbounded_channel();
         tokio::spawn(async move {
-            while let Some(value) = request_rx.recv().await {
-                if let Err(err) = tx
-                    .send(Message::Binary(serde_cbor::to_vec(&value).unwrap()))
-                    .await
-                {
+            let mut ping = tokio::time::interval(heartbeat.interval);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let message = tokio::select!(
+                    value = request_rx.recv() => match value {
+                        Some(value) => match serde_cbor::to_vec(&value) {
+                            Ok(bytes) => Message::Binary(bytes),
+                            Err(err) => {
+                                tracing::error!("Failed to encode message: {err}");
+                                return;
+                            }
+                        },
+                        None => return,
+                    },
+                    _ = ping.tick() => Message::Ping(vec![]),
+                );
+                if let Err(err) = tx.send(message).await {
                     tracing::error!("Failed to send to websocket: {err}");
                     return;
                 }
@@ -80,21 +268,31 @@ pub mod axum {
         tokio::spawn(async move {
             loop {
                 tokio::select!(
-                     value = rx.next() => {
-                        match value {
-                            Some(Ok(Message::Binary(value))) => {
-                                let a = serde_cbor::from_slice(&value).unwrap();
-                                response_tx.send(a).unwrap();
+                    value = timeout(heartbeat.timeout, rx.next()) => {
+                        let Ok(value) = value else {
+                            response_tx.send(Err(ReceiveError::TimedOut { after: heartbeat.timeout })).ok();
+                            return;
+                        };
+                        let received = match value {
+                            Some(Ok(Message::Binary(value))) => error::decode(&value),
+                            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                            Some(Ok(Message::Close(_))) | None => {
+                                response_tx.send(Err(ReceiveError::Closed)).ok();
+                                return;
                             }
-                            Some(Ok(other)) => tracing::error!("Unexpected message {other:?}"),
+                            Some(Ok(Message::Text(_))) => Err(ReceiveError::UnexpectedMessage { kind: "text" }),
                             Some(Err(err)) => {
                                 // TODO: ughhh seriously axum??
-                                let a = format!("{:?}",err);
-                                if !a.contains("ResetWithoutClosingHandshake") {
-                                    tracing::error!("Failed to receive message from websocket: {err:?}");
+                                // axum does not expose the error kind, and a peer resetting the connection is just a close.
+                                if format!("{err:?}").contains("ResetWithoutClosingHandshake") {
+                                    response_tx.send(Err(ReceiveError::Closed)).ok();
+                                    return;
                                 }
+                                Err(ReceiveError::Transport { reason: err.to_string() })
                             }
-                            None => return,
+                        };
+                        if response_tx.send(received).is_err() {
+                            return;
                         }
                     }
                     _ = response_tx.closed() => {
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+/// A single named measurement of a bench e.g. walltime, instructions, cycles, cache misses, max RSS or throughput
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Metric {
+    /// e.g. `walltime`
+    pub name: String,
+    /// e.g. `ns`
+    /// Empty for metrics that are a plain count, such as `instructions`.
+    pub unit: String,
+    /// The best estimate of the metric for a single iteration of the bench
+    pub value: f64,
+    /// The individual samples that `value` was derived from, if the measurement method produces samples.
+    pub samples: Option<Vec<f64>>,
+}
+
+/// A metric the runner attempted to measure but could not
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnavailableMetric {
+    pub name: String,
+    pub reason: String,
+}
+
+impl Metric {
+    /// The value used for the `type` key when the metric is stored as a series in an archive e.g. `walltime (ns)`
+    pub fn type_key(&self) -> String {
+        if self.unit.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, self.unit)
+        }
+    }
+}
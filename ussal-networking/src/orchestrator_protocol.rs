@@ -1,12 +1,42 @@
+use crate::binary_hash::BinaryHash;
+use crate::metric::{Metric, UnavailableMetric};
+use crate::transfer::{BinaryChunk, TransferProgress};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Sent by the client
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ClientMessage {
+    /// Every chunk of a binary must be sent before any `JobRequest` that refers to the binary.
+    /// Binaries are kept until the client disconnects, so each binary only needs to be uploaded once per connection.
+    /// Wait for the `OrchestratorMessage::UploadProgress` of each chunk before sending the next.
+    UploadBinary {
+        auth_token: Uuid,
+        chunk: BinaryChunk,
+    },
+    RunJob(JobRequest),
+}
+
+/// Sent by the orchestrator
+#[derive(Serialize, Deserialize, Debug)]
+pub enum OrchestratorMessage {
+    JobResponse(JobResponse),
+    /// Acknowledges a chunk of a `ClientMessage::UploadBinary`
+    UploadProgress(TransferProgress),
+    /// The upload must be restarted from the first chunk
+    UploadFailed {
+        binary_hash: BinaryHash,
+        reason: String,
+    },
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobRequest {
     pub auth_token: Uuid,
     pub job_id: Uuid,
-    pub binary: Vec<u8>,
+    /// A binary previously sent in `ClientMessage::UploadBinary`
+    pub binary_hash: BinaryHash,
     pub machine_type: String,
 }
 
@@ -27,11 +57,20 @@ pub enum JobResult {
     JobComplete,
     /// Entire job failed
     JobError(String),
+    /// A chunk of the job's binary was received by a runner that did not have it cached
+    BinaryTransfer {
+        machine_type: String,
+        progress: TransferProgress,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BenchComplete {
     pub bench_name: String,
-    pub wall_time: f32,
+    pub metrics: Vec<Metric>,
+    /// Metrics that the runner attempted to measure but could not
+    pub unavailable: Vec<UnavailableMetric>,
     pub keys: HashMap<String, String>,
+    /// Describes the hardware and OS of the runner that ran the bench
+    pub runner_fingerprint: String,
 }
@@ -1,20 +1,28 @@
+use crate::binary_hash::BinaryHash;
+use crate::metric::{Metric, UnavailableMetric};
+use crate::transfer::{BinaryChunk, TransferProgress};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JobRequest {
     pub job_id: Uuid,
-    pub binary: Vec<u8>,
+    pub binary_hash: BinaryHash,
     pub ty: JobRequestType,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum JobRequestType {
-    RunBench { bench_name: String },
+    RunBench {
+        bench_name: String,
+    },
     ListBenches,
+    /// Sent before `RunBench` or `ListBenches` when the runner did not list `binary_hash` in its handshake.
+    /// Answered with `JobResponseType::BinaryChunkReceived`.
+    BinaryChunk(BinaryChunk),
 }
 
-/// One JobResponse will be sent per JobRequest
+/// One JobResponse will be sent per JobRequest, after which the runner reconnects unless the request was a `BinaryChunk`
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobResponse {
     pub job_id: Uuid,
@@ -23,9 +31,14 @@ pub struct JobResponse {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum JobResponseType {
-    Handshake { machine_type: String },
+    Handshake {
+        machine_type: String,
+        /// The binaries the runner already has, which the orchestrator does not need to send
+        cached_binaries: Vec<BinaryHash>,
+    },
     RunBench(BenchComplete),
     ListBenches(Vec<String>),
+    BinaryChunkReceived(TransferProgress),
     Error(String),
 }
 
@@ -35,6 +48,9 @@ impl JobResponseType {
             JobResponseType::RunBench(x) => Ok(x),
             JobResponseType::ListBenches(_) => Err("Unexpected response ListBenches".to_owned()),
             JobResponseType::Handshake { .. } => Err("Unexpected handshake".to_owned()),
+            JobResponseType::BinaryChunkReceived(_) => {
+                Err("Unexpected response BinaryChunkReceived".to_owned())
+            }
             JobResponseType::Error(err) => Err(err.clone()),
         }
     }
@@ -44,6 +60,9 @@ impl JobResponseType {
             JobResponseType::ListBenches(benches) => Ok(benches),
             JobResponseType::RunBench(_) => Err("Unexpected response RunBench".to_owned()),
             JobResponseType::Handshake { .. } => Err("Unexpected handshake".to_owned()),
+            JobResponseType::BinaryChunkReceived(_) => {
+                Err("Unexpected response BinaryChunkReceived".to_owned())
+            }
             JobResponseType::Error(err) => Err(err.clone()),
         }
     }
@@ -51,5 +70,9 @@ impl JobResponseType {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BenchComplete {
-    pub wall_time: f32,
+    pub metrics: Vec<Metric>,
+    /// Metrics that the runner attempted to measure but could not
+    pub unavailable: Vec<UnavailableMetric>,
+    /// Describes the hardware and OS of the runner
+    pub runner_fingerprint: String,
 }
@@ -0,0 +1,165 @@
+//! Bench binaries with debug info can be hundreds of megabytes, beyond the message size limits of the websocket libraries.
+//! So rather than one message they are sent as a series of deflate compressed chunks.
+//! The receiver acknowledges each chunk with a `TransferProgress` before the next is sent,
+//! which keeps slow links such as a Raspberry Pi's from buffering the whole binary and lets the sender report progress.
+
+use crate::binary_hash::BinaryHash;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The uncompressed size of each chunk
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BinaryChunk {
+    pub binary_hash: BinaryHash,
+    /// Chunks are sent in order starting from 0
+    pub index: u32,
+    pub count: u32,
+    /// The uncompressed size of the whole binary
+    pub total_bytes: u64,
+    /// Deflate compressed
+    pub data: Vec<u8>,
+}
+
+/// The data is left out as it is not readable and can be large
+impl fmt::Debug for BinaryChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BinaryChunk")
+            .field("binary_hash", &self.binary_hash)
+            .field("index", &self.index)
+            .field("count", &self.count)
+            .field("total_bytes", &self.total_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sent by the receiver after each chunk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub binary_hash: BinaryHash,
+    pub received_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl TransferProgress {
+    pub fn is_complete(&self) -> bool {
+        self.received_bytes == self.total_bytes
+    }
+}
+
+/// Splits `binary` into compressed chunks, each chunk is only compressed when it is reached
+pub fn chunks(binary: &[u8]) -> impl Iterator<Item = BinaryChunk> + '_ {
+    let binary_hash = BinaryHash::of(binary);
+    // An empty binary is still sent as a single empty chunk so that the receiver finds out about it
+    let count = binary.len().div_ceil(CHUNK_SIZE).max(1);
+    (0..count).map(move |index| {
+        let end = binary.len().min((index + 1) * CHUNK_SIZE);
+        BinaryChunk {
+            binary_hash,
+            index: index as u32,
+            count: count as u32,
+            total_bytes: binary.len() as u64,
+            data: miniz_oxide::deflate::compress_to_vec(&binary[index * CHUNK_SIZE..end], 6),
+        }
+    })
+}
+
+/// Reassembles the chunks of a single binary
+pub struct ChunkReceiver {
+    max_bytes: u64,
+    binary: Vec<u8>,
+    next_index: u32,
+    count: u32,
+    total_bytes: u64,
+}
+
+impl ChunkReceiver {
+    /// Binaries stated to be larger than `max_bytes` are rejected at their first chunk
+    pub fn new(max_bytes: u64) -> Self {
+        ChunkReceiver {
+            max_bytes,
+            binary: vec![],
+            next_index: 0,
+            count: 0,
+            total_bytes: 0,
+        }
+    }
+
+    /// Errors if the chunk is out of order, disagrees with the earlier chunks or the binary does not match its hash, after which the transfer must be restarted
+    pub fn receive(&mut self, chunk: BinaryChunk) -> Result<TransferProgress, String> {
+        let hash = chunk.binary_hash;
+        // The size and count come from the peer, so are checked before anything is received.
+        // Otherwise a chunk could claim a huge binary, or a count of 0 that never completes.
+        if self.next_index == 0 {
+            if chunk.total_bytes > self.max_bytes {
+                return Err(format!(
+                    "Binary {hash} is {} bytes which is larger than the limit of {} bytes",
+                    chunk.total_bytes, self.max_bytes
+                ));
+            }
+            let expected_count = chunk.total_bytes.div_ceil(CHUNK_SIZE as u64).max(1);
+            if chunk.count as u64 != expected_count {
+                return Err(format!(
+                    "Binary {hash} of {} bytes must be sent in {expected_count} chunks but was sent in {}",
+                    chunk.total_bytes, chunk.count
+                ));
+            }
+        }
+        if chunk.index != self.next_index || (self.next_index > 0 && chunk.count != self.count) {
+            return Err(format!(
+                "Expected chunk {} of binary {hash} but received chunk {} of {}",
+                self.next_index, chunk.index, chunk.count
+            ));
+        }
+        if self.next_index > 0 && chunk.total_bytes != self.total_bytes {
+            return Err(format!(
+                "Chunk {} of binary {hash} states a size of {} bytes but earlier chunks stated {} bytes",
+                chunk.index, chunk.total_bytes, self.total_bytes
+            ));
+        }
+        // The limit stops a malicious peer from sending a small chunk that decompresses to fill all memory
+        let data = miniz_oxide::inflate::decompress_to_vec_with_limit(&chunk.data, CHUNK_SIZE)
+            .map_err(|e| {
+                format!(
+                    "Failed to decompress chunk {} of binary {hash}: {e}",
+                    chunk.index
+                )
+            })?;
+        self.binary.extend_from_slice(&data);
+        self.next_index += 1;
+        self.count = chunk.count;
+        self.total_bytes = chunk.total_bytes;
+
+        if self.binary.len() as u64 > chunk.total_bytes {
+            return Err(format!(
+                "Binary {hash} is larger than its stated size of {} bytes",
+                chunk.total_bytes
+            ));
+        }
+        if self.is_complete() && self.binary.len() as u64 != chunk.total_bytes {
+            return Err(format!(
+                "Binary {hash} is smaller than its stated size of {} bytes",
+                chunk.total_bytes
+            ));
+        }
+        if self.is_complete() && BinaryHash::of(&self.binary) != hash {
+            return Err(format!("Received binary does not match its hash {hash}"));
+        }
+        Ok(TransferProgress {
+            binary_hash: hash,
+            received_bytes: self.binary.len() as u64,
+            total_bytes: chunk.total_bytes,
+        })
+    }
+
+    /// Whether every chunk has been received
+    pub fn is_complete(&self) -> bool {
+        self.next_index > 0 && self.next_index == self.count
+    }
+
+    /// The binary, only whole once `is_complete`
+    pub fn into_binary(self) -> Vec<u8> {
+        self.binary
+    }
+}
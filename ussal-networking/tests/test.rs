@@ -0,0 +1,376 @@
+use axum::extract::WebSocketUpgrade;
+use axum::routing::get;
+use axum::Router;
+use futures_util::{SinkExt, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::Message;
+use ussal_networking::binary_hash::BinaryHash;
+use ussal_networking::hello::{Hello, Negotiated, Protocol};
+use ussal_networking::transfer::{self, BinaryChunk, ChunkReceiver, TransferProgress, CHUNK_SIZE};
+use ussal_networking::{Heartbeat, ReceiveError};
+
+fn hello(software: &str, min_version: u32, max_version: u32, capabilities: &[&str]) -> Hello {
+    Hello {
+        protocol: Protocol::Runner,
+        min_version,
+        max_version,
+        capabilities: capabilities.iter().map(|x| (*x).to_owned()).collect(),
+        software: software.to_owned(),
+    }
+}
+
+#[test]
+fn test_negotiate_versions() {
+    // (ours, peer, expected version or the software the error says to upgrade)
+    let cases = [
+        ((1, 3), (2, 5), Ok(3)),
+        ((2, 5), (1, 3), Ok(3)),
+        ((1, 3), (1, 3), Ok(3)),
+        ((3, 3), (1, 3), Ok(3)),
+        ((1, 1), (1, 1), Ok(1)),
+        // The peer is too old
+        ((3, 4), (1, 2), Err("upgrade peer")),
+        // The orchestrator is too old
+        ((1, 2), (3, 4), Err("upgrade orchestrator")),
+    ];
+    for ((our_min, our_max), (peer_min, peer_max), expected) in cases {
+        let ours = hello("orchestrator", our_min, our_max, &[]);
+        let peer = hello("peer", peer_min, peer_max, &[]);
+        let result = ours.negotiate(&peer);
+        match expected {
+            Ok(version) => assert_eq!(
+                result,
+                Ok(Negotiated {
+                    version,
+                    capabilities: vec![]
+                }),
+                "ours {our_min}..={our_max} peer {peer_min}..={peer_max}"
+            ),
+            Err(upgrade) => {
+                let reason = result.unwrap_err();
+                assert!(
+                    reason.ends_with(upgrade),
+                    "ours {our_min}..={our_max} peer {peer_min}..={peer_max}: {reason}"
+                );
+                assert!(
+                    reason.contains(&format!(
+                        "peer supports protocol versions {peer_min}..={peer_max}"
+                    )),
+                    "{reason}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_negotiate_protocol_mismatch() {
+    let ours = hello("orchestrator", 1, 3, &[]);
+    let peer = Hello {
+        protocol: Protocol::Orchestrator,
+        ..hello("ussal-client 0.1.0", 1, 3, &[])
+    };
+    let reason = ours.negotiate(&peer).unwrap_err();
+    assert!(reason.starts_with("ussal-client 0.1.0 connected to the Runner endpoint but speaks the Orchestrator protocol"), "{reason}");
+}
+
+#[test]
+fn test_negotiate_capabilities() {
+    let ours = hello(
+        "orchestrator",
+        1,
+        3,
+        &["compression", "heartbeat", "resume"],
+    );
+    let peer = hello("peer", 1, 3, &["resume", "unknown", "compression"]);
+    let negotiated = ours.negotiate(&peer).unwrap();
+    assert_eq!(negotiated.capabilities, vec!["compression", "resume"]);
+
+    let peer = hello("peer", 1, 3, &[]);
+    assert!(ours.negotiate(&peer).unwrap().capabilities.is_empty());
+}
+
+/// Garbage, a text message, a valid message and then a close, as sent by a broken or malicious peer
+async fn send_bad_messages<S>(socket: &mut tokio_tungstenite::WebSocketStream<S>)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    socket
+        .send(Message::Binary(vec![0xff, 0x00]))
+        .await
+        .unwrap();
+    socket
+        .send(Message::Text("hello".to_owned()))
+        .await
+        .unwrap();
+    let valid = serde_cbor::to_vec(&"still connected".to_owned()).unwrap();
+    socket.send(Message::Binary(valid)).await.unwrap();
+    socket.close(None).await.unwrap();
+}
+
+/// Checks that bad messages are received as errors without stopping the read task, and that the last message is `Closed`
+async fn assert_bad_messages(mut rx: UnboundedReceiver<Result<String, ReceiveError>>) {
+    assert!(matches!(
+        rx.recv().await,
+        Some(Err(ReceiveError::Decode { .. }))
+    ));
+    assert_eq!(
+        rx.recv().await,
+        Some(Err(ReceiveError::UnexpectedMessage { kind: "text" }))
+    );
+    assert_eq!(rx.recv().await, Some(Ok("still connected".to_owned())));
+    assert_eq!(rx.recv().await, Some(Err(ReceiveError::Closed)));
+    assert_eq!(rx.recv().await, None);
+}
+
+#[tokio::test]
+async fn test_read_task_reports_bad_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("ws://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        send_bad_messages(&mut socket).await;
+    });
+
+    let (socket, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+    let (_tx, rx) = ussal_networking::spawn_read_write_tasks::<String, String>(socket).await;
+    assert_bad_messages(rx).await;
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_axum_read_task_reports_bad_messages() {
+    let (rx_tx, mut rx_rx) = mpsc::unbounded_channel();
+    let app = Router::new().route(
+        "/",
+        get(move |ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |socket| async move {
+                let (tx, rx) =
+                    ussal_networking::axum::spawn_read_write_tasks::<String, String>(socket).await;
+                rx_tx.send((tx, rx)).unwrap();
+            })
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+    let (_tx, rx) = rx_rx.recv().await.unwrap();
+    send_bad_messages(&mut socket).await;
+    assert_bad_messages(rx).await;
+}
+
+fn transfer(binary: &[u8]) -> Result<(Vec<u8>, Vec<TransferProgress>), String> {
+    let mut receiver = ChunkReceiver::new(u64::MAX);
+    let mut progress = vec![];
+    for chunk in transfer::chunks(binary) {
+        progress.push(receiver.receive(chunk)?);
+    }
+    assert!(receiver.is_complete());
+    Ok((receiver.into_binary(), progress))
+}
+
+/// Not very compressible, so that chunks are not trivially small
+fn binary(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7919 % 251) as u8).collect()
+}
+
+#[test]
+fn test_transfer_round_trip() {
+    for len in [0, 1000, CHUNK_SIZE, 2 * CHUNK_SIZE + 123] {
+        let binary = binary(len);
+        let (received, progress) = transfer(&binary).unwrap();
+        assert!(
+            received == binary,
+            "binary of {len} bytes was not received intact"
+        );
+        assert_eq!(progress.len(), len.div_ceil(CHUNK_SIZE).max(1));
+        assert!(progress.last().unwrap().is_complete());
+        assert!(progress[..progress.len() - 1]
+            .iter()
+            .all(|x| !x.is_complete()));
+        assert!(progress.iter().all(|x| x.total_bytes == len as u64));
+    }
+}
+
+fn assert_rejected(chunks: Vec<BinaryChunk>, expected: &str) {
+    let mut receiver = ChunkReceiver::new(u64::MAX);
+    let err = chunks
+        .into_iter()
+        .map(|chunk| receiver.receive(chunk))
+        .find_map(Result::err)
+        .expect("the transfer should be rejected");
+    assert!(
+        err.contains(expected),
+        "{err:?} does not contain {expected:?}"
+    );
+}
+
+#[test]
+fn test_transfer_rejects_out_of_order() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(3 * CHUNK_SIZE)).collect();
+    chunks.swap(1, 2);
+    assert_rejected(chunks, "Expected chunk 1");
+}
+
+#[test]
+fn test_transfer_rejects_changed_count() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(3 * CHUNK_SIZE)).collect();
+    chunks[1].count = 4;
+    assert_rejected(chunks, "Expected chunk 1");
+}
+
+#[test]
+fn test_transfer_rejects_changed_total_bytes() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(3 * CHUNK_SIZE)).collect();
+    chunks[2].total_bytes += 1;
+    assert_rejected(chunks, "earlier chunks stated");
+}
+
+#[test]
+fn test_transfer_rejects_decompression_bomb() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(1000)).collect();
+    chunks[0].data = miniz_oxide::deflate::compress_to_vec(&vec![0; 64 * CHUNK_SIZE], 6);
+    assert!(chunks[0].data.len() < CHUNK_SIZE);
+    assert_rejected(chunks, "Failed to decompress");
+}
+
+#[test]
+fn test_transfer_rejects_hash_mismatch() {
+    let mut binary = binary(2 * CHUNK_SIZE);
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary).collect();
+    binary[CHUNK_SIZE + 10] ^= 1;
+    chunks[1].data = miniz_oxide::deflate::compress_to_vec(&binary[CHUNK_SIZE..], 6);
+    assert_rejected(chunks, "does not match its hash");
+}
+
+#[test]
+fn test_transfer_rejects_wrong_size() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(1000)).collect();
+    chunks[0].total_bytes = 999;
+    assert_rejected(chunks, "larger than its stated size");
+
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(1000)).collect();
+    chunks[0].total_bytes = 1001;
+    assert_rejected(chunks, "smaller than its stated size");
+}
+
+#[test]
+fn test_transfer_rejects_zero_count() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(0)).collect();
+    chunks[0].count = 0;
+    assert_rejected(chunks, "must be sent in 1 chunks but was sent in 0");
+}
+
+#[test]
+fn test_transfer_rejects_count_not_matching_size() {
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(2 * CHUNK_SIZE)).collect();
+    for chunk in &mut chunks {
+        chunk.count = 3;
+    }
+    assert_rejected(chunks, "must be sent in 2 chunks but was sent in 3");
+
+    // A huge count would otherwise let the binary grow well past its stated size
+    let mut chunks: Vec<BinaryChunk> = transfer::chunks(&binary(1000)).collect();
+    chunks[0].count = u32::MAX;
+    assert_rejected(chunks, "must be sent in 1 chunks");
+}
+
+#[test]
+fn test_transfer_rejects_binary_over_limit() {
+    let binary = binary(2 * CHUNK_SIZE);
+    let mut receiver = ChunkReceiver::new(2 * CHUNK_SIZE as u64 - 1);
+    let err = receiver
+        .receive(transfer::chunks(&binary).next().unwrap())
+        .unwrap_err();
+    assert!(
+        err.contains("larger than the limit"),
+        "{err:?} does not mention the limit"
+    );
+
+    let mut receiver = ChunkReceiver::new(2 * CHUNK_SIZE as u64);
+    for chunk in transfer::chunks(&binary) {
+        receiver.receive(chunk).unwrap();
+    }
+    assert!(receiver.is_complete());
+}
+
+const FAST_HEARTBEAT: Heartbeat = Heartbeat {
+    interval: Duration::from_millis(20),
+    timeout: Duration::from_millis(200),
+};
+
+/// Connects to a loopback peer that runs `peer` on its end of the websocket.
+/// The returned sender must be kept alive, dropping it stops the pings.
+async fn connect_to_peer<F, Fut>(
+    peer: F,
+) -> (
+    UnboundedSender<String>,
+    UnboundedReceiver<Result<String, ReceiveError>>,
+)
+where
+    F: FnOnce(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        peer(tokio_tungstenite::accept_async(stream).await.unwrap()).await;
+    });
+    let (socket, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+    let (tx, rx) = ussal_networking::spawn_read_write_tasks_with_heartbeat::<String, String>(
+        socket,
+        FAST_HEARTBEAT,
+    )
+    .await;
+    (tx, rx)
+}
+
+#[tokio::test]
+async fn test_heartbeat_times_out_silent_peer() {
+    // Pongs are only sent while reading, so a peer that never reads is like one that has hung or lost its network
+    let (_tx, mut rx) = connect_to_peer(|socket| async move {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        drop(socket);
+    })
+    .await;
+    let started = Instant::now();
+    assert_eq!(
+        rx.recv().await,
+        Some(Err(ReceiveError::TimedOut {
+            after: FAST_HEARTBEAT.timeout
+        }))
+    );
+    assert!(started.elapsed() >= FAST_HEARTBEAT.timeout);
+    assert_eq!(rx.recv().await, None);
+}
+
+#[tokio::test]
+async fn test_heartbeat_keeps_idle_peer() {
+    // The peer sends nothing itself but replies to pings
+    let (_tx, mut rx) =
+        connect_to_peer(|mut socket| async move { while socket.next().await.is_some() {} }).await;
+    let received = tokio::time::timeout(5 * FAST_HEARTBEAT.timeout, rx.recv()).await;
+    assert!(received.is_err(), "received {received:?}");
+}
+
+#[test]
+fn test_binary_hash_parse() {
+    let hash = BinaryHash::of(b"binary");
+    assert_eq!(hash.to_string().parse::<BinaryHash>(), Ok(hash));
+
+    assert!("".parse::<BinaryHash>().is_err());
+    assert!("notes.txt".parse::<BinaryHash>().is_err());
+    assert!(hash
+        .to_string()
+        .to_uppercase()
+        .parse::<BinaryHash>()
+        .is_err());
+    assert!(format!("{hash}0").parse::<BinaryHash>().is_err());
+    assert!("é".repeat(32).parse::<BinaryHash>().is_err());
+}
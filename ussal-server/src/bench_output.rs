@@ -0,0 +1,277 @@
+use std::fmt;
+use ussal_bench::report::BenchReport;
+use ussal_networking::metric::Metric;
+
+/// The harness used by a bench binary
+#[derive(Clone, Copy, PartialEq)]
+pub enum BenchHarness {
+    UssalBench,
+    Criterion,
+}
+
+pub struct BenchOutput {
+    /// Always contains a `walltime` metric in nanoseconds
+    pub metrics: Vec<Metric>,
+    pub wall_time_nanoseconds: f64,
+    pub harness: BenchHarness,
+}
+
+#[derive(Debug)]
+pub enum BenchOutputError {
+    /// A line in the results file could not be parsed as a `BenchReport`
+    InvalidReport {
+        line_number: usize,
+        error: serde_json::Error,
+    },
+    /// The results file contained reports but none of them were for the requested bench
+    MissingBench { bench_name: String },
+    /// The report for the bench did not include the walltime metric
+    MissingWalltime { bench_name: String },
+    /// The value was reported in a unit that we do not know how to convert
+    UnknownUnit { unit: String },
+    /// No results file was written and criterion's human readable output could not be found either
+    CriterionTimeNotFound { output: String },
+    /// criterion's human readable output was found but did not have the expected layout
+    CriterionTimeMalformed { line: String },
+}
+
+impl fmt::Display for BenchOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchOutputError::InvalidReport { line_number, error } => {
+                write!(f, "Failed to parse line {line_number} of the bench results file: {error}")
+            }
+            BenchOutputError::MissingBench { bench_name } => {
+                write!(f, "The bench results file did not contain results for {bench_name:?}")
+            }
+            BenchOutputError::MissingWalltime { bench_name } => {
+                write!(f, "The results for {bench_name:?} did not include walltime")
+            }
+            BenchOutputError::UnknownUnit { unit } => write!(f, "Unknown time unit {unit:?}"),
+            BenchOutputError::CriterionTimeNotFound { output } => write!(
+                f,
+                "No bench results file was written and could not find criterion's wall time in the output: {output:?}"
+            ),
+            BenchOutputError::CriterionTimeMalformed { line } => {
+                write!(f, "Failed to parse criterion's wall time from the line: {line:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BenchOutputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BenchOutputError::InvalidReport { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Extract the results of the bench from the contents of the results file.
+/// If the bench binary did not write any results, it is assumed to be a criterion bench and its human readable output is parsed instead.
+pub fn parse_run_bench(
+    bench_name: &str,
+    results: &str,
+    output: &str,
+) -> Result<BenchOutput, BenchOutputError> {
+    if results.trim().is_empty() {
+        return parse_criterion_output(output);
+    }
+
+    let reports = parse_reports(results)?;
+    let report = reports
+        .iter()
+        .find(|report| report.bench_name == bench_name)
+        .ok_or_else(|| BenchOutputError::MissingBench {
+            bench_name: bench_name.to_owned(),
+        })?;
+    if !report
+        .metrics
+        .iter()
+        .any(|metric| metric.kind == "walltime")
+    {
+        return Err(BenchOutputError::MissingWalltime {
+            bench_name: bench_name.to_owned(),
+        });
+    }
+
+    let mut metrics = vec![];
+    let mut wall_time_nanoseconds = 0.0;
+    for metric in &report.metrics {
+        if metric.kind == "walltime" {
+            // Normalize walltime to nanoseconds so that series are comparable regardless of how the harness reports them.
+            let to_ns = to_nanoseconds(1.0, &metric.unit)?;
+            wall_time_nanoseconds = metric.summary.median * to_ns;
+            metrics.push(Metric {
+                name: metric.kind.clone(),
+                unit: "ns".to_owned(),
+                value: wall_time_nanoseconds,
+                samples: Some(metric.samples.iter().map(|x| x * to_ns).collect()),
+            });
+        } else {
+            metrics.push(Metric {
+                name: metric.kind.clone(),
+                unit: metric.unit.clone(),
+                value: metric.summary.median,
+                samples: Some(metric.samples.clone()),
+            });
+        }
+    }
+
+    Ok(BenchOutput {
+        metrics,
+        wall_time_nanoseconds,
+        harness: BenchHarness::UssalBench,
+    })
+}
+
+pub fn parse_reports(results: &str) -> Result<Vec<BenchReport>, BenchOutputError> {
+    results
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|error| BenchOutputError::InvalidReport {
+                line_number: i + 1,
+                error,
+            })
+        })
+        .collect()
+}
+
+/// Parses criterion's human readable output which looks like:
+/// `mat4 mul vec4          time:   [1.2268 ns 1.2299 ns 1.2334 ns]`
+/// The middle value is criterion's best estimate.
+fn parse_criterion_output(output: &str) -> Result<BenchOutput, BenchOutputError> {
+    let line = output
+        .lines()
+        .rev()
+        .find(|line| line.contains("time: "))
+        .ok_or_else(|| BenchOutputError::CriterionTimeNotFound {
+            output: output.to_owned(),
+        })?;
+    let malformed = || BenchOutputError::CriterionTimeMalformed {
+        line: line.to_owned(),
+    };
+
+    let estimates = line
+        .split_once('[')
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .map(|(estimates, _)| estimates)
+        .ok_or_else(malformed)?;
+    let words: Vec<&str> = estimates.split_whitespace().collect();
+    if words.len() != 6 {
+        return Err(malformed());
+    }
+    let value: f64 = words[2].parse().map_err(|_| malformed())?;
+    let wall_time_nanoseconds = to_nanoseconds(value, words[3])?;
+
+    Ok(BenchOutput {
+        metrics: vec![Metric {
+            name: "walltime".to_owned(),
+            unit: "ns".to_owned(),
+            value: wall_time_nanoseconds,
+            samples: None,
+        }],
+        wall_time_nanoseconds,
+        harness: BenchHarness::Criterion,
+    })
+}
+
+fn to_nanoseconds(value: f64, unit: &str) -> Result<f64, BenchOutputError> {
+    match unit {
+        "ps" => Ok(value / 1_000.0),
+        "ns" => Ok(value),
+        "µs" | "us" => Ok(value * 1_000.0),
+        "ms" => Ok(value * 1_000_000.0),
+        "s" => Ok(value * 1_000_000_000.0),
+        unit => Err(BenchOutputError::UnknownUnit {
+            unit: unit.to_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = r#"{"bench_name":"sum","metrics":[{"kind":"walltime","unit":"us","samples":[1.0,2.0,3.0],"summary":{"mean":2.0,"median":2.0,"std_dev":1.0,"min":1.0,"max":3.0}}]}"#;
+
+    const CRITERION_OUTPUT: &str = "\
+Benchmarking mat4 mul vec4: Analyzing
+mat4 mul vec4          time:   [1.2268 ns 1.2299 ns 1.2334 ns]
+";
+
+    #[test]
+    fn parse_valid_report() {
+        let results = format!("{REPORT}\n");
+        let output = parse_run_bench("sum", &results, "").unwrap();
+        assert!(output.harness == BenchHarness::UssalBench);
+        assert_eq!(output.wall_time_nanoseconds, 2000.0);
+        assert_eq!(output.metrics.len(), 1);
+        assert_eq!(output.metrics[0].unit, "ns");
+        assert_eq!(
+            output.metrics[0].samples,
+            Some(vec![1000.0, 2000.0, 3000.0])
+        );
+    }
+
+    #[test]
+    fn parse_missing_bench() {
+        let err = parse_run_bench("product", REPORT, "").err().unwrap();
+        assert!(
+            matches!(&err, BenchOutputError::MissingBench { bench_name } if bench_name == "product"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn parse_malformed_json() {
+        let results = format!("{REPORT}\n{{\"bench_name\":\n");
+        let err = parse_run_bench("sum", &results, "").err().unwrap();
+        assert!(
+            matches!(err, BenchOutputError::InvalidReport { line_number: 2, .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn parse_criterion_fallback() {
+        let output = parse_run_bench("mat4 mul vec4", "", CRITERION_OUTPUT).unwrap();
+        assert!(output.harness == BenchHarness::Criterion);
+        assert_eq!(output.wall_time_nanoseconds, 1.2299);
+        assert_eq!(output.metrics[0].samples, None);
+    }
+
+    #[test]
+    fn parse_criterion_not_found() {
+        let err = parse_run_bench("sum", "", "running 0 tests\n")
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, BenchOutputError::CriterionTimeNotFound { .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn parse_criterion_malformed() {
+        let output = "mat4 mul vec4          time:   [1.2268 ns 1.2299]\n";
+        let err = parse_run_bench("mat4 mul vec4", "", output).err().unwrap();
+        assert!(
+            matches!(err, BenchOutputError::CriterionTimeMalformed { .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn parse_criterion_unknown_unit() {
+        let output = "mat4 mul vec4          time:   [1.2 ks 1.3 ks 1.4 ks]\n";
+        let err = parse_run_bench("mat4 mul vec4", "", output).err().unwrap();
+        assert!(
+            matches!(&err, BenchOutputError::UnknownUnit { unit } if unit == "ks"),
+            "{err:?}"
+        );
+    }
+}
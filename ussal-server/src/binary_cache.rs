@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use ussal_networking::binary_hash::BinaryHash;
+
+/// Bench binaries kept on disk by a runner so that the orchestrator only sends binaries the runner has not seen yet.
+/// When the cache exceeds its capacity the least recently used binaries are removed.
+/// Recency is kept in each file's modification time so that it survives restarts.
+pub struct BinaryCache {
+    dir: PathBuf,
+    capacity_bytes: u64,
+    /// Ordered from least to most recently used
+    entries: Vec<(BinaryHash, u64)>,
+}
+
+impl BinaryCache {
+    /// Binaries left in `dir` by a previous run are kept if their contents still match their hash.
+    /// Only files named after a hash, or a partly written `{hash}.partial`, are considered, everything else in `dir` is left alone.
+    /// `dir` must not be shared with another runner, as each runner removes binaries it does not know about.
+    pub fn new(dir: PathBuf, capacity_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!(e).context(format!("Failed to create {dir:?}")))?;
+        let read_dir = std::fs::read_dir(&dir)
+            .map_err(|e| anyhow!(e).context(format!("Failed to read {dir:?}")))?;
+
+        let mut entries = vec![];
+        for entry in read_dir {
+            let entry = entry.map_err(|e| anyhow!(e).context(format!("Failed to read {dir:?}")))?;
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+                continue;
+            };
+            let is_file = entry.file_type().map(|x| x.is_file()).unwrap_or(false);
+            if !is_file {
+                continue;
+            }
+
+            if let Some(hash) = name.strip_suffix(".partial") {
+                if hash.parse::<BinaryHash>().is_ok() {
+                    // A binary that was only partly written when the runner was killed
+                    std::fs::remove_file(&path)
+                        .map_err(|e| anyhow!(e).context(format!("Failed to remove {path:?}")))?;
+                }
+                continue;
+            }
+            let Ok(hash) = name.parse::<BinaryHash>() else {
+                continue;
+            };
+            let binary = std::fs::read(&path)
+                .map_err(|e| anyhow!(e).context(format!("Failed to read {path:?}")))?;
+            if BinaryHash::of(&binary) == hash {
+                let modified = std::fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|e| anyhow!(e).context(format!("Failed to read {path:?}")))?;
+                entries.push((modified, hash, binary.len() as u64));
+            } else {
+                tracing::warn!("Removing {path:?} as its contents do not match its name");
+                std::fs::remove_file(&path)
+                    .map_err(|e| anyhow!(e).context(format!("Failed to remove {path:?}")))?;
+            }
+        }
+        entries.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut cache = BinaryCache {
+            dir,
+            capacity_bytes,
+            entries: entries
+                .into_iter()
+                .map(|(_, hash, size)| (hash, size))
+                .collect(),
+        };
+        // The capacity may have been lowered since the previous run
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    pub fn hashes(&self) -> Vec<BinaryHash> {
+        self.entries.iter().map(|(hash, _)| *hash).collect()
+    }
+
+    /// Returns None if the binary is not cached
+    pub fn get(&mut self, hash: BinaryHash) -> Result<Option<Vec<u8>>> {
+        let Some(i) = self.entries.iter().position(|(x, _)| *x == hash) else {
+            return Ok(None);
+        };
+        let entry = self.entries.remove(i);
+        self.entries.push(entry);
+
+        let path = self.path(hash);
+        let binary = std::fs::read(&path)
+            .map_err(|e| anyhow!(e).context(format!("Failed to read {path:?}")))?;
+        // Losing the recency only makes the binary more likely to be removed, so failing to update it is not an error
+        std::fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .ok();
+        Ok(Some(binary))
+    }
+
+    /// The most recently inserted binary is always kept, even if it alone exceeds the capacity
+    pub fn insert(&mut self, hash: BinaryHash, binary: &[u8]) -> Result<()> {
+        if self.entries.iter().any(|(x, _)| *x == hash) {
+            return Ok(());
+        }
+        // Written under a different name first so that a binary is never found only partly written
+        let path = self.path(hash);
+        let partial_path = self.dir.join(format!("{hash}.partial"));
+        std::fs::write(&partial_path, binary)
+            .map_err(|e| anyhow!(e).context(format!("Failed to write {partial_path:?}")))?;
+        std::fs::rename(&partial_path, &path)
+            .map_err(|e| anyhow!(e).context(format!("Failed to write {path:?}")))?;
+        self.entries.push((hash, binary.len() as u64));
+        self.evict()
+    }
+
+    fn evict(&mut self) -> Result<()> {
+        while self.entries.len() > 1
+            && self.entries.iter().map(|(_, size)| size).sum::<u64>() > self.capacity_bytes
+        {
+            let (hash, _) = self.entries.remove(0);
+            let path = self.path(hash);
+            std::fs::remove_file(&path)
+                .map_err(|e| anyhow!(e).context(format!("Failed to remove {path:?}")))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, hash: BinaryHash) -> PathBuf {
+        self.dir.join(hash.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory per test, so that tests can run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ussal-binary-cache-test-{name}"));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn insert(cache: &mut BinaryCache, binary: &[u8]) -> BinaryHash {
+        let hash = BinaryHash::of(binary);
+        cache.insert(hash, binary).unwrap();
+        hash
+    }
+
+    #[test]
+    fn evicts_least_recently_inserted() {
+        let dir = test_dir("evict");
+        let mut cache = BinaryCache::new(dir.clone(), 10).unwrap();
+        let a = insert(&mut cache, b"aaaa");
+        let b = insert(&mut cache, b"bbbb");
+        let c = insert(&mut cache, b"cccc");
+
+        assert_eq!(cache.hashes(), vec![b, c]);
+        assert_eq!(cache.get(a).unwrap(), None);
+        assert!(!dir.join(a.to_string()).exists());
+        assert_eq!(cache.get(b).unwrap(), Some(b"bbbb".to_vec()));
+    }
+
+    #[test]
+    fn get_refreshes_recency() {
+        let mut cache = BinaryCache::new(test_dir("recency"), 10).unwrap();
+        let a = insert(&mut cache, b"aaaa");
+        let b = insert(&mut cache, b"bbbb");
+        cache.get(a).unwrap().unwrap();
+        let c = insert(&mut cache, b"cccc");
+
+        assert_eq!(cache.hashes(), vec![a, c]);
+        assert_eq!(cache.get(b).unwrap(), None);
+    }
+
+    #[test]
+    fn keeps_oversized_binary() {
+        let mut cache = BinaryCache::new(test_dir("oversized"), 2).unwrap();
+        let a = insert(&mut cache, b"aaaa");
+        assert_eq!(cache.hashes(), vec![a]);
+        assert_eq!(cache.get(a).unwrap(), Some(b"aaaa".to_vec()));
+
+        let b = insert(&mut cache, b"bbbb");
+        assert_eq!(cache.hashes(), vec![b]);
+    }
+
+    #[test]
+    fn survives_restart() {
+        let dir = test_dir("restart");
+        let mut cache = BinaryCache::new(dir.clone(), 100).unwrap();
+        let a = insert(&mut cache, b"aaaa");
+        let b = insert(&mut cache, b"bbbb");
+        drop(cache);
+        // A binary that was corrupted while the runner was stopped is removed rather than run
+        std::fs::write(dir.join(b.to_string()), b"corrupt").unwrap();
+        let partial = dir.join(format!("{}.partial", BinaryHash::of(b"cccc")));
+        std::fs::write(&partial, b"cc").unwrap();
+
+        let mut cache = BinaryCache::new(dir.clone(), 100).unwrap();
+        assert_eq!(cache.hashes(), vec![a]);
+        assert_eq!(cache.get(a).unwrap(), Some(b"aaaa".to_vec()));
+        assert!(!dir.join(b.to_string()).exists());
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = test_dir("other-files");
+        std::fs::create_dir_all(dir.join("subdirectory")).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"keep me").unwrap();
+        std::fs::write(dir.join("notes.partial"), b"keep me").unwrap();
+
+        let mut cache = BinaryCache::new(dir.clone(), 2).unwrap();
+        insert(&mut cache, b"aaaa");
+        insert(&mut cache, b"bbbb");
+        drop(cache);
+        let cache = BinaryCache::new(dir.clone(), 2).unwrap();
+
+        assert_eq!(cache.hashes(), vec![BinaryHash::of(b"bbbb")]);
+        assert!(dir.join("subdirectory").is_dir());
+        assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"keep me");
+        assert_eq!(
+            std::fs::read(dir.join("notes.partial")).unwrap(),
+            b"keep me"
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+pub const DEFAULT_MAX_UPLOAD_MB: u64 = 4096;
 
 #[derive(Subcommand)]
 pub enum Mode {
@@ -10,6 +13,17 @@ pub enum Mode {
         /// An arbitrary string containing the machine-type of this runner, the runner will only receive jobs that request this machine-type
         #[clap(long, required = true)]
         machine_type: String,
+
+        /// How many megabytes of bench binaries to keep, so that binaries run again are not sent again.
+        /// The least recently used binaries are removed first.
+        #[clap(long, default_value_t = 1024)]
+        binary_cache_mb: u64,
+
+        /// Bench binaries are cached in a `ussal-binary-cache` subdirectory of this directory.
+        /// Defaults to `UssalRunner` in the user's cache directory.
+        /// Runners on the same machine must each use their own directory.
+        #[clap(long)]
+        binary_cache_dir: Option<PathBuf>,
     },
     Orchestrator {
         /// Domains used in the letsencrypt certificate
@@ -32,6 +46,10 @@ pub enum Mode {
         /// This option is useful for a setup where you have another webserver such as nginx running on the same machine as the ussal-server that provides https.
         #[clap(long)]
         disable_https: bool,
+
+        /// The most megabytes of bench binaries a single client may upload while it is connected.
+        #[clap(long, default_value_t = DEFAULT_MAX_UPLOAD_MB)]
+        max_upload_mb: u64,
     },
     OrchestratorAndRunner {
         /// Domains used in the letsencrypt certificate
@@ -54,6 +72,10 @@ pub enum Mode {
         /// This option is useful for a setup where you have another webserver such as nginx running on the same machine as the ussal-server that provides https.
         #[clap(long)]
         disable_https: bool,
+
+        /// The most megabytes of bench binaries a single client may upload while it is connected.
+        #[clap(long, default_value_t = DEFAULT_MAX_UPLOAD_MB)]
+        max_upload_mb: u64,
     },
     /// Install or reinstall the ussal runner to this machine.
     ///
@@ -75,6 +97,7 @@ pub struct OrchestratorArgs {
     pub email: Option<String>,
     pub port: Option<u16>,
     pub disable_https: bool,
+    pub max_upload_mb: u64,
 }
 
 impl Mode {
@@ -85,23 +108,27 @@ impl Mode {
                 email,
                 port,
                 disable_https,
+                max_upload_mb,
             }
             | Mode::OrchestratorAndRunner {
                 domains,
                 email,
                 port,
                 disable_https,
+                max_upload_mb,
             } => OrchestratorArgs {
                 domains: domains.clone(),
                 email: email.clone(),
                 port: *port,
                 disable_https: *disable_https,
+                max_upload_mb: *max_upload_mb,
             },
             Mode::DestructivelyInstallRunner { email, domains } => OrchestratorArgs {
                 email: email.clone(),
                 domains: domains.clone(),
                 port: None,
                 disable_https: false,
+                max_upload_mb: DEFAULT_MAX_UPLOAD_MB,
             },
             _ => unreachable!("This must only be called when it is known to use orchestrator args"),
         }
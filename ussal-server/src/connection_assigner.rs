@@ -1,11 +1,18 @@
+use std::collections::HashSet;
+use std::future::poll_fn;
+use std::task::Poll;
 use tokio::sync::{mpsc, oneshot};
+use ussal_networking::binary_hash::BinaryHash;
 use ussal_networking::runner_protocol as runner_proto;
+use ussal_networking::ReceiveError;
 
 #[derive(Debug)]
 pub struct Connection {
     pub tx: mpsc::UnboundedSender<runner_proto::JobRequest>,
-    pub rx: mpsc::UnboundedReceiver<runner_proto::JobResponse>,
+    pub rx: mpsc::UnboundedReceiver<Result<runner_proto::JobResponse, ReceiveError>>,
     pub machine_type: String,
+    /// Binaries the runner already has, so do not need to be sent to it
+    pub cached_binaries: HashSet<BinaryHash>,
 }
 
 #[derive(Debug)]
@@ -34,6 +41,13 @@ pub async fn task(
                 waiting_connections.push(connection);
             } else {
                 return
+            },
+            (connection_i, message) = next_idle_message(&mut waiting_connections) => {
+                let connection = waiting_connections.remove(connection_i);
+                match message {
+                    Some(Err(err)) => tracing::warn!("Evicting {} runner: {err}", connection.machine_type),
+                    message => tracing::warn!("Evicting {} runner, it sent {message:?} while idle", connection.machine_type),
+                }
             }
         );
 
@@ -46,6 +60,26 @@ pub async fn task(
     }
 }
 
+/// Resolves once any waiting runner sends a message.
+/// Runners only send messages in response to requests, so an idle runner that sends anything,
+/// usually the final `ReceiveError::Closed` or `ReceiveError::TimedOut`, can no longer be given jobs.
+async fn next_idle_message(
+    connections: &mut [Connection],
+) -> (
+    usize,
+    Option<Result<runner_proto::JobResponse, ReceiveError>>,
+) {
+    poll_fn(|cx| {
+        for (i, connection) in connections.iter_mut().enumerate() {
+            if let Poll::Ready(message) = connection.rx.poll_recv(cx) {
+                return Poll::Ready((i, message));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
 fn find_match(connections: &[Connection], requests: &[Request]) -> Option<(usize, usize)> {
     for (connection_i, connection) in connections.iter().enumerate() {
         for (request_i, request) in requests.iter().enumerate() {
@@ -56,3 +90,70 @@ fn find_match(connections: &[Connection], requests: &[Request]) -> Option<(usize
     }
     None
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The runner's end of a `Connection`
+    pub(crate) struct FakeRunner {
+        pub requests: mpsc::UnboundedReceiver<runner_proto::JobRequest>,
+        pub responses: mpsc::UnboundedSender<Result<runner_proto::JobResponse, ReceiveError>>,
+    }
+
+    pub(crate) fn connection(
+        machine_type: &str,
+        cached_binaries: &[BinaryHash],
+    ) -> (Connection, FakeRunner) {
+        let (tx, requests) = mpsc::unbounded_channel();
+        let (responses, rx) = mpsc::unbounded_channel();
+        let connection = Connection {
+            tx,
+            rx,
+            machine_type: machine_type.to_owned(),
+            cached_binaries: cached_binaries.iter().copied().collect(),
+        };
+        (
+            connection,
+            FakeRunner {
+                requests,
+                responses,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn evicts_dead_idle_runners() {
+        let errors = [
+            ReceiveError::TimedOut {
+                after: Duration::from_secs(60),
+            },
+            ReceiveError::Closed,
+        ];
+        for error in errors {
+            let (request_tx, request_rx) = mpsc::unbounded_channel();
+            let (connection_tx, connection_rx) = mpsc::unbounded_channel();
+            tokio::spawn(task(request_rx, connection_rx));
+
+            let (dead, mut dead_runner) = connection("x86", &[]);
+            connection_tx.send(dead).unwrap();
+            dead_runner.responses.send(Err(error)).unwrap();
+            // Evicting the connection drops it, which closes the channel its requests are sent on
+            assert!(dead_runner.requests.recv().await.is_none());
+
+            let (tx, rx) = oneshot::channel();
+            request_tx
+                .send(Request {
+                    tx,
+                    machine_type: "x86".to_owned(),
+                })
+                .unwrap();
+            let alive_hash = BinaryHash([1; 32]);
+            let (alive, _alive_runner) = connection("x86", &[alive_hash]);
+            connection_tx.send(alive).unwrap();
+            let assigned = rx.await.unwrap();
+            assert!(assigned.cached_binaries.contains(&alive_hash));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::cli::SandboxMode;
+use crate::system::{run_command, run_wrapped_sandboxed_binary, Wrapper};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+/// Iterations run by the baseline process, the instructions it retires are subtracted from the measured process.
+/// This removes the instructions spent on process startup and bench setup.
+const BASELINE_ITERATIONS: u64 = 10;
+
+/// Aim for the measured process to spend roughly this long running the bench routine.
+const TARGET_NANOSECONDS: f64 = 100_000_000.0;
+
+/// Counts the instructions retired by a single iteration of a ussal-bench bench.
+///
+/// The bench binary is run twice with a fixed number of iterations under a tool that counts the instructions retired by the whole process.
+/// The per iteration count is then derived from the difference between the two runs.
+pub fn count(
+    counter: &InstructionCounter,
+    sandbox_mode: SandboxMode,
+    binary_path: &str,
+    bench_name: &str,
+    results_path: &str,
+    wall_time_nanoseconds: f64,
+) -> Result<f64> {
+    let iterations =
+        ((TARGET_NANOSECONDS / wall_time_nanoseconds.max(1.0)) as u64).clamp(100, 1_000_000);
+
+    let baseline = counter.count_process(
+        sandbox_mode,
+        binary_path,
+        bench_name,
+        results_path,
+        BASELINE_ITERATIONS,
+    )?;
+    let measured = counter.count_process(
+        sandbox_mode,
+        binary_path,
+        bench_name,
+        results_path,
+        BASELINE_ITERATIONS + iterations,
+    )?;
+
+    Ok(measured.saturating_sub(baseline) as f64 / iterations as f64)
+}
+
+#[derive(Debug)]
+pub enum InstructionCounter {
+    /// Hardware performance counters via `perf stat`
+    Perf,
+    /// Instruction emulation via valgrind's cachegrind tool, used when hardware counters are not accessible e.g. in a VM.
+    Cachegrind { valgrind_path: String },
+}
+
+impl InstructionCounter {
+    /// Spawns processes to probe for a working tool, so is done once when the runner starts rather than for every bench
+    pub fn detect() -> Result<InstructionCounter> {
+        let perf_works = run_command(
+            "perf",
+            &["stat", "-x", ",", "-e", "instructions:u", "--", "true"],
+        )
+        .map(|output| parse_perf_output(&output).is_ok())
+        .unwrap_or(false);
+        if perf_works {
+            return Ok(InstructionCounter::Perf);
+        }
+
+        if let Some(valgrind_path) = find_in_path("valgrind") {
+            return Ok(InstructionCounter::Cachegrind {
+                valgrind_path: valgrind_path.to_str().unwrap().to_owned(),
+            });
+        }
+
+        Err(anyhow!(
+            "This runner can not count instructions, install `perf` with access to hardware performance counters or install `valgrind`"
+        ))
+    }
+
+    fn count_process(
+        &self,
+        sandbox_mode: SandboxMode,
+        binary_path: &str,
+        bench_name: &str,
+        results_path: &str,
+        iterations: u64,
+    ) -> Result<u64> {
+        let iterations = iterations.to_string();
+        let args = [
+            "--bench",
+            "--exact",
+            bench_name,
+            "--iterations",
+            &iterations,
+        ];
+        match self {
+            InstructionCounter::Perf => {
+                let output = run_wrapped_sandboxed_binary(
+                    sandbox_mode,
+                    Wrapper::Outside {
+                        args: &["perf", "stat", "-x", ",", "-e", "instructions:u", "--"],
+                    },
+                    binary_path,
+                    &args,
+                    results_path,
+                )?;
+                parse_perf_output(&output)
+            }
+            InstructionCounter::Cachegrind { valgrind_path } => {
+                let output = run_wrapped_sandboxed_binary(
+                    sandbox_mode,
+                    Wrapper::Inside {
+                        args: &[
+                            valgrind_path.as_str(),
+                            "--tool=cachegrind",
+                            "--cache-sim=no",
+                            "--cachegrind-out-file=/dev/null",
+                        ],
+                        // Depending on the distro valgrind keeps its tools in either /usr/lib or /usr/libexec
+                        read_only: if Path::new("/usr/libexec/valgrind").exists() {
+                            &["/usr/libexec/valgrind"]
+                        } else {
+                            &[]
+                        },
+                        writable: &["/dev/null"],
+                    },
+                    binary_path,
+                    &args,
+                    results_path,
+                )?;
+                parse_cachegrind_output(&output)
+            }
+        }
+    }
+}
+
+/// `perf stat -x ,` reports the count as the first field of a csv line e.g. `1234567,,instructions:u,1000,100.00,,`
+fn parse_perf_output(output: &str) -> Result<u64> {
+    let line = output
+        .lines()
+        .find(|line| line.contains(",instructions"))
+        .ok_or_else(|| anyhow!("Could not find instruction count in perf output: {output:?}"))?;
+    let count = line.split(',').next().unwrap();
+    count
+        .parse()
+        .map_err(|_| anyhow!("perf could not count instructions, it reported {count:?}"))
+}
+
+/// cachegrind reports the count in a line like `==1234== I   refs:      1,234,567`
+fn parse_cachegrind_output(output: &str) -> Result<u64> {
+    let count = output
+        .lines()
+        .find_map(|line| {
+            line.split_once(" refs:")
+                .filter(|(prefix, _)| prefix.trim_end().ends_with('I'))
+        })
+        .map(|(_, count)| count.trim().replace(',', ""))
+        .ok_or_else(|| {
+            anyhow!("Could not find instruction count in cachegrind output: {output:?}")
+        })?;
+    count
+        .parse()
+        .map_err(|_| anyhow!("Failed to parse cachegrind instruction count {count:?}"))
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_perf() {
+        let output = "hello from the bench\n1234567,,instructions:u,1001234,100.00,,\n";
+        assert_eq!(parse_perf_output(output).unwrap(), 1234567);
+    }
+
+    #[test]
+    fn parse_perf_not_supported() {
+        let output = "<not supported>,,instructions:u,0,100.00,,\n";
+        let err = parse_perf_output(output).unwrap_err();
+        assert!(err.to_string().contains("<not supported>"), "{err}");
+    }
+
+    #[test]
+    fn parse_perf_missing() {
+        assert!(parse_perf_output("bench output only\n").is_err());
+    }
+
+    #[test]
+    fn parse_cachegrind() {
+        let output = "\
+==4242== Cachegrind, a high-precision tracing profiler
+==4242== Copyright (C) 2002-2017, and GNU GPL'd, by Nicholas Nethercote et al.
+==4242== Command: ./binary-under-test --bench --exact sum --iterations 110
+==4242==
+==4242==
+==4242== I   refs:      1,234,567
+";
+        assert_eq!(parse_cachegrind_output(output).unwrap(), 1234567);
+    }
+
+    #[test]
+    fn parse_cachegrind_missing() {
+        let output = "==4242== Cachegrind, a high-precision tracing profiler\n";
+        assert!(parse_cachegrind_output(output).is_err());
+    }
+}
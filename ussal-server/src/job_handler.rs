@@ -1,15 +1,23 @@
 use crate::cli::SandboxMode;
 use crate::connection_assigner::{Connection, Request};
+use crate::instructions::InstructionCounter;
 use crate::AppState;
+use anyhow::Result;
 use axum::extract::ws::WebSocket;
 use axum::extract::{State, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use futures::future::join_all;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{oneshot, Semaphore};
+use ussal_networking::binary_hash::BinaryHash;
+use ussal_networking::hello::{Hello, Protocol};
 use ussal_networking::orchestrator_protocol as orch_proto;
 use ussal_networking::runner_protocol as runner_proto;
+use ussal_networking::transfer::{self, ChunkReceiver};
+use ussal_networking::ReceiveError;
+use uuid::Uuid;
 
 pub async fn run_job(
     ws: WebSocketUpgrade,
@@ -18,20 +26,84 @@ pub async fn run_job(
     ws.on_upgrade(|stream| run_job_websocket(stream, state))
 }
 
-async fn run_job_websocket(stream: WebSocket, state: Arc<AppState>) {
+async fn run_job_websocket(mut stream: WebSocket, state: Arc<AppState>) {
+    let hello = Hello::new(Protocol::Orchestrator, crate::SOFTWARE);
+    if let Err(err) = ussal_networking::axum::accept_hello(&mut stream, &hello).await {
+        tracing::error!("{:?}", err.context("Rejected client"));
+        return;
+    }
     let (mut tx, mut rx) = ussal_networking::axum::spawn_read_write_tasks::<
-        orch_proto::JobResponse,
-        orch_proto::JobRequest,
+        orch_proto::OrchestratorMessage,
+        orch_proto::ClientMessage,
     >(stream)
     .await;
 
-    while let Some(request) = rx.recv().await {
-        process_request(&mut tx, &request, &state).await;
+    // Binaries uploaded by this client, kept until it disconnects
+    let mut binaries: HashMap<BinaryHash, Arc<Vec<u8>>> = HashMap::new();
+    let mut uploads: HashMap<BinaryHash, ChunkReceiver> = HashMap::new();
+    while let Some(message) = rx.recv().await {
+        match message {
+            Ok(orch_proto::ClientMessage::UploadBinary { auth_token, chunk }) => {
+                let binary_hash = chunk.binary_hash;
+                let response = if state.config.borrow().tokens.contains(&auth_token) {
+                    receive_upload(&mut uploads, &mut binaries, chunk, state.max_upload_bytes)
+                } else {
+                    Err("Invalid auth token".to_owned())
+                };
+                let message = match response {
+                    Ok(progress) => orch_proto::OrchestratorMessage::UploadProgress(progress),
+                    Err(reason) => orch_proto::OrchestratorMessage::UploadFailed {
+                        binary_hash,
+                        reason,
+                    },
+                };
+                tx.send(message).unwrap();
+            }
+            Ok(orch_proto::ClientMessage::RunJob(request)) => {
+                process_request(&mut tx, &request, &binaries, &state).await
+            }
+            Err(ReceiveError::Closed) => return,
+            // The client is not trusted, so rather than guessing at what it meant, disconnect it.
+            Err(err) => {
+                tracing::warn!("Disconnecting client: {err}");
+                return;
+            }
+        }
+    }
+}
+
+/// Once the last chunk is received the binary is moved from `uploads` to `binaries`.
+/// The binaries a client uploads, including the one in progress, are limited to `max_upload_bytes` in total.
+fn receive_upload(
+    uploads: &mut HashMap<BinaryHash, ChunkReceiver>,
+    binaries: &mut HashMap<BinaryHash, Arc<Vec<u8>>>,
+    chunk: transfer::BinaryChunk,
+    max_upload_bytes: u64,
+) -> Result<transfer::TransferProgress, String> {
+    let binary_hash = chunk.binary_hash;
+    // Clients upload one binary at a time, so any other unfinished upload was abandoned
+    uploads.retain(|hash, _| *hash == binary_hash);
+    let uploaded: u64 = binaries.values().map(|x| x.len() as u64).sum();
+    let receiver = uploads
+        .entry(binary_hash)
+        .or_insert_with(|| ChunkReceiver::new(max_upload_bytes.saturating_sub(uploaded)));
+    match receiver.receive(chunk) {
+        Ok(progress) => {
+            if receiver.is_complete() {
+                let binary = uploads.remove(&binary_hash).unwrap().into_binary();
+                binaries.insert(binary_hash, Arc::new(binary));
+            }
+            Ok(progress)
+        }
+        Err(reason) => {
+            uploads.remove(&binary_hash);
+            Err(reason)
+        }
     }
 }
 
 async fn fail_job(
-    tx: &mut UnboundedSender<orch_proto::JobResponse>,
+    tx: &mut UnboundedSender<orch_proto::OrchestratorMessage>,
     request: &orch_proto::JobRequest,
     message: String,
 ) {
@@ -39,28 +111,44 @@ async fn fail_job(
         job_id: request.job_id,
         result: orch_proto::JobResult::JobError(message),
     };
-    tx.send(response).unwrap();
+    tx.send(orch_proto::OrchestratorMessage::JobResponse(response))
+        .unwrap();
 }
 
 async fn process_request(
-    tx: &mut UnboundedSender<orch_proto::JobResponse>,
+    tx: &mut UnboundedSender<orch_proto::OrchestratorMessage>,
     request: &orch_proto::JobRequest,
+    binaries: &HashMap<BinaryHash, Arc<Vec<u8>>>,
     state: &AppState,
 ) {
     if !state.config.borrow().tokens.contains(&request.auth_token) {
         fail_job(tx, request, "Invalid auth token".to_owned()).await;
         return;
     }
+    let Some(binary) = binaries.get(&request.binary_hash) else {
+        let message = format!(
+            "Binary {} must be uploaded before the job that runs it",
+            request.binary_hash
+        );
+        fail_job(tx, request, message).await;
+        return;
+    };
     let list_request = runner_proto::JobRequest {
         job_id: request.job_id,
-        binary: request.binary.clone(),
+        binary_hash: request.binary_hash,
         ty: runner_proto::JobRequestType::ListBenches,
     };
     let job_response = state
         .handler
-        .run_job_request(list_request, &request.machine_type)
+        .run_job_request(list_request, binary, &request.machine_type, tx)
         .await;
-    let benches = job_response.ty.get_list_benches().unwrap();
+    let benches = match job_response.ty.get_list_benches() {
+        Ok(benches) => benches,
+        Err(err) => {
+            fail_job(tx, request, format!("Failed to list benches: {err}")).await;
+            return;
+        }
+    };
 
     let run = benches.iter().map(|bench| {
         let tx = tx.clone();
@@ -68,12 +156,15 @@ async fn process_request(
             let machine_type = &request.machine_type;
             let request = runner_proto::JobRequest {
                 job_id: request.job_id,
-                binary: request.binary.clone(),
+                binary_hash: request.binary_hash,
                 ty: runner_proto::JobRequestType::RunBench {
                     bench_name: bench.clone(),
                 },
             };
-            let job_response = state.handler.run_job_request(request, machine_type).await;
+            let job_response = state
+                .handler
+                .run_job_request(request, binary, machine_type, &tx)
+                .await;
             let response = orch_proto::JobResponse {
                 job_id: job_response.job_id,
                 result: job_response
@@ -82,15 +173,16 @@ async fn process_request(
                     .map(|x| {
                         orch_proto::JobResult::BenchComplete(orch_proto::BenchComplete {
                             bench_name: bench.clone(),
-                            keys: [("type".to_owned(), "walltime (ns)".to_owned())]
-                                .into_iter()
-                                .collect(),
-                            wall_time: x.wall_time,
+                            metrics: x.metrics.clone(),
+                            unavailable: x.unavailable.clone(),
+                            keys: HashMap::new(),
+                            runner_fingerprint: x.runner_fingerprint.clone(),
                         })
                     })
                     .unwrap_or_else(orch_proto::JobResult::BenchError),
             };
-            tx.send(response).unwrap();
+            tx.send(orch_proto::OrchestratorMessage::JobResponse(response))
+                .unwrap();
         }
     });
     join_all(run).await;
@@ -99,46 +191,83 @@ async fn process_request(
         job_id: request.job_id,
         result: orch_proto::JobResult::JobComplete,
     };
-    tx.send(response).unwrap();
+    tx.send(orch_proto::OrchestratorMessage::JobResponse(response))
+        .unwrap();
 }
 
+/// A request that keeps losing its runner, e.g. because the bench crashes the machine, fails rather than being requeued forever
+const MAX_RUNNER_ATTEMPTS: usize = 3;
+
 pub enum HandlerState {
     #[allow(dead_code)]
     Orchestrator(OrchestratorState),
     OrchestratorAndRunner {
         semaphore: Semaphore,
         sandbox_mode: SandboxMode,
+        instruction_counter: Arc<Result<InstructionCounter>>,
     },
 }
 
 impl HandlerState {
+    /// `binary` is only sent to runners that do not already have it, with the progress of the transfer sent to `progress_tx`
     async fn run_job_request(
         &self,
         request: runner_proto::JobRequest,
+        binary: &Arc<Vec<u8>>,
         machine_type: &str,
+        progress_tx: &UnboundedSender<orch_proto::OrchestratorMessage>,
     ) -> runner_proto::JobResponse {
         match self {
-            HandlerState::Orchestrator(state) => loop {
-                let mut connection = state.get_connection(machine_type).await;
-                connection
-                    .tx
-                    .send(request.clone())
-                    .expect("assigner task should never die");
-                match connection.rx.recv().await {
-                    Some(response) => return response,
-                    None => {
-                        tracing::error!("Connection to runner was lost before it sent a response")
+            HandlerState::Orchestrator(state) => {
+                // Each failed attempt requeues the request to the next available runner of the same machine type
+                for _ in 0..MAX_RUNNER_ATTEMPTS {
+                    let mut connection = state.get_connection(machine_type).await;
+                    if !connection.cached_binaries.contains(&request.binary_hash) {
+                        if let Err(err) =
+                            send_binary(&mut connection, request.job_id, binary, progress_tx).await
+                        {
+                            tracing::error!("Failed to send binary to runner, requeuing: {err}");
+                            continue;
+                        }
+                    }
+                    if connection.tx.send(request.clone()).is_err() {
+                        tracing::error!("Connection to runner was lost, requeuing");
+                        continue;
+                    }
+                    match connection.rx.recv().await {
+                        Some(Ok(response)) => return response,
+                        Some(Err(ReceiveError::Closed)) | None => tracing::error!(
+                            "Connection to runner was lost before it sent a response, requeuing"
+                        ),
+                        // Dropping the connection disconnects the runner, which will reconnect and try again.
+                        Some(Err(err)) => tracing::error!(
+                            "Disconnecting runner, failed to receive its response, requeuing: {err}"
+                        ),
                     }
                 }
-            },
+                runner_proto::JobResponse {
+                    job_id: request.job_id,
+                    ty: runner_proto::JobResponseType::Error(format!(
+                        "Lost the connection to {MAX_RUNNER_ATTEMPTS} {machine_type} runners while running the job"
+                    )),
+                }
+            }
             HandlerState::OrchestratorAndRunner {
                 sandbox_mode,
                 semaphore,
+                instruction_counter,
             } => {
                 let _permit = semaphore.acquire().await.unwrap();
                 let sandbox_mode = *sandbox_mode;
+                let instruction_counter = instruction_counter.clone();
+                let binary = binary.clone();
                 tokio::task::spawn_blocking(move || {
-                    crate::runner::run_job_request(sandbox_mode, &request)
+                    crate::runner::run_job_request(
+                        sandbox_mode,
+                        &instruction_counter,
+                        &request,
+                        &binary,
+                    )
                 })
                 .await
                 .unwrap()
@@ -147,6 +276,53 @@ impl HandlerState {
     }
 }
 
+/// Sends `binary` one chunk at a time, waiting for the runner to acknowledge each chunk before sending the next
+async fn send_binary(
+    connection: &mut Connection,
+    job_id: Uuid,
+    binary: &[u8],
+    progress_tx: &UnboundedSender<orch_proto::OrchestratorMessage>,
+) -> Result<(), String> {
+    for chunk in transfer::chunks(binary) {
+        let request = runner_proto::JobRequest {
+            job_id,
+            binary_hash: chunk.binary_hash,
+            ty: runner_proto::JobRequestType::BinaryChunk(chunk),
+        };
+        connection
+            .tx
+            .send(request)
+            .map_err(|_| ReceiveError::Closed.to_string())?;
+        let progress = match connection.rx.recv().await {
+            Some(Ok(runner_proto::JobResponse {
+                ty: runner_proto::JobResponseType::BinaryChunkReceived(progress),
+                ..
+            })) => progress,
+            Some(Ok(runner_proto::JobResponse {
+                ty: runner_proto::JobResponseType::Error(err),
+                ..
+            })) => return Err(err),
+            Some(Ok(other)) => {
+                return Err(format!("Expected BinaryChunkReceived but was {other:?}"))
+            }
+            Some(Err(err)) => return Err(err.to_string()),
+            None => return Err(ReceiveError::Closed.to_string()),
+        };
+        let response = orch_proto::JobResponse {
+            job_id,
+            result: orch_proto::JobResult::BinaryTransfer {
+                machine_type: connection.machine_type.clone(),
+                progress,
+            },
+        };
+        // The client disconnecting is handled once the job completes
+        progress_tx
+            .send(orch_proto::OrchestratorMessage::JobResponse(response))
+            .ok();
+    }
+    Ok(())
+}
+
 pub struct OrchestratorState {
     request_tx: UnboundedSender<Request>,
     pub connection_tx: UnboundedSender<Connection>,
@@ -177,3 +353,142 @@ impl OrchestratorState {
         rx.await.unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_assigner::tests::{connection, FakeRunner};
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::task::JoinHandle;
+
+    fn orchestrator() -> (HandlerState, UnboundedSender<Connection>) {
+        let (request_tx, request_rx) = unbounded_channel();
+        let (connection_tx, connection_rx) = unbounded_channel();
+        tokio::spawn(crate::connection_assigner::task(request_rx, connection_rx));
+        let state = OrchestratorState::new(request_tx, connection_tx.clone());
+        (HandlerState::Orchestrator(state), connection_tx)
+    }
+
+    /// Disconnects as soon as it is given a request, resolves to whether it was given one
+    fn dying_runner(mut runner: FakeRunner) -> JoinHandle<bool> {
+        tokio::spawn(async move {
+            let received = runner.requests.recv().await.is_some();
+            runner.responses.send(Err(ReceiveError::Closed)).ok();
+            received
+        })
+    }
+
+    fn list_benches_runner(mut runner: FakeRunner) {
+        tokio::spawn(async move {
+            while let Some(request) = runner.requests.recv().await {
+                let response = runner_proto::JobResponse {
+                    job_id: request.job_id,
+                    ty: runner_proto::JobResponseType::ListBenches(vec!["bench".to_owned()]),
+                };
+                runner.responses.send(Ok(response)).unwrap();
+            }
+        });
+    }
+
+    async fn list_benches(
+        handler: &HandlerState,
+        binary: &Arc<Vec<u8>>,
+    ) -> runner_proto::JobResponse {
+        let request = runner_proto::JobRequest {
+            job_id: Uuid::new_v4(),
+            binary_hash: BinaryHash::of(binary),
+            ty: runner_proto::JobRequestType::ListBenches,
+        };
+        let (progress_tx, _progress_rx) = unbounded_channel();
+        handler
+            .run_job_request(request, binary, "x86", &progress_tx)
+            .await
+    }
+
+    #[tokio::test]
+    async fn requeues_to_another_runner_of_the_machine_type() {
+        let (handler, connection_tx) = orchestrator();
+        let binary = Arc::new(b"binary".to_vec());
+        let hash = BinaryHash::of(&binary);
+
+        // Runners are assigned in the order they connect, so the dying runner is tried first
+        let (dying, runner) = connection("x86", &[hash]);
+        connection_tx.send(dying).unwrap();
+        let dying = dying_runner(runner);
+        let (other_machine, mut other_machine_runner) = connection("arm", &[hash]);
+        let (alive, alive_runner) = connection("x86", &[hash]);
+        connection_tx.send(other_machine).unwrap();
+        connection_tx.send(alive).unwrap();
+        list_benches_runner(alive_runner);
+
+        let response = list_benches(&handler, &binary).await;
+        assert_eq!(
+            response.ty.get_list_benches().unwrap(),
+            &vec!["bench".to_owned()]
+        );
+        assert!(dying.await.unwrap());
+        assert!(other_machine_runner.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_after_max_runner_attempts() {
+        let (handler, connection_tx) = orchestrator();
+        let binary = Arc::new(b"binary".to_vec());
+        let hash = BinaryHash::of(&binary);
+
+        let mut dying = vec![];
+        for _ in 0..MAX_RUNNER_ATTEMPTS {
+            let (connection, runner) = connection("x86", &[hash]);
+            connection_tx.send(connection).unwrap();
+            dying.push(dying_runner(runner));
+        }
+        let (spare, mut spare_runner) = connection("x86", &[hash]);
+        connection_tx.send(spare).unwrap();
+
+        let response = list_benches(&handler, &binary).await;
+        let err = response.ty.get_list_benches().unwrap_err();
+        assert!(
+            err.contains("Lost the connection to 3 x86 runners"),
+            "{err}"
+        );
+        for runner in dying {
+            assert!(runner.await.unwrap());
+        }
+        assert!(spare_runner.requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn limits_total_uploads_per_client() {
+        let mut uploads = HashMap::new();
+        let mut binaries = HashMap::new();
+        let mut upload = |binary: &[u8]| {
+            transfer::chunks(binary)
+                .map(|chunk| receive_upload(&mut uploads, &mut binaries, chunk, 10))
+                .last()
+                .unwrap()
+        };
+
+        assert!(upload(b"aaaaaa").unwrap().is_complete());
+        let err = upload(b"bbbbbb").unwrap_err();
+        assert!(err.contains("larger than the limit of 4 bytes"), "{err}");
+        assert!(upload(b"cccc").unwrap().is_complete());
+        assert_eq!(binaries.len(), 2);
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn drops_abandoned_uploads() {
+        let mut uploads = HashMap::new();
+        let mut binaries = HashMap::new();
+        let abandoned = vec![1; transfer::CHUNK_SIZE + 1];
+        let first_chunk = transfer::chunks(&abandoned).next().unwrap();
+        receive_upload(&mut uploads, &mut binaries, first_chunk, u64::MAX).unwrap();
+        assert_eq!(uploads.len(), 1);
+
+        for chunk in transfer::chunks(b"binary") {
+            receive_upload(&mut uploads, &mut binaries, chunk, u64::MAX).unwrap();
+        }
+        assert!(uploads.is_empty());
+        assert_eq!(binaries.len(), 1);
+    }
+}
@@ -10,10 +10,13 @@ use std::sync::Arc;
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::Semaphore;
 
+mod bench_output;
+mod binary_cache;
 mod cli;
 mod config;
 mod connection_assigner;
 mod install;
+mod instructions;
 mod job_handler;
 mod letsencrypt;
 mod request_job;
@@ -22,6 +25,9 @@ mod status_page;
 mod system;
 mod tracing_panic_handler;
 
+/// Identifies this build in protocol hellos
+const SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -39,7 +45,18 @@ async fn run(args: Args) {
         Mode::Runner {
             address,
             machine_type,
-        } => runner::runner(args.sandbox_mode, address, machine_type).await,
+            binary_cache_mb,
+            binary_cache_dir,
+        } => {
+            runner::runner(
+                args.sandbox_mode,
+                address,
+                machine_type,
+                *binary_cache_mb,
+                binary_cache_dir.clone(),
+            )
+            .await
+        }
         Mode::Orchestrator { .. } => orchestrator(args, false).await,
         Mode::OrchestratorAndRunner { .. } => orchestrator(args, true).await,
         Mode::DestructivelyInstallRunner { .. } => install::install_runner(args),
@@ -51,6 +68,7 @@ async fn orchestrator(args: Args, runner: bool) {
         HandlerState::OrchestratorAndRunner {
             semaphore: Semaphore::new(1),
             sandbox_mode: args.sandbox_mode,
+            instruction_counter: Arc::new(runner::detect_instruction_counter()),
         }
     } else {
         let (request_tx, request_rx) = unbounded_channel();
@@ -90,6 +108,7 @@ async fn orchestrator(args: Args, runner: bool) {
 pub struct AppState {
     handler: HandlerState,
     config: ReloadableOrchestratorConfig,
+    max_upload_bytes: u64,
 }
 
 impl AppState {
@@ -97,6 +116,7 @@ impl AppState {
         AppState {
             handler,
             config: ReloadableOrchestratorConfig::load(args),
+            max_upload_bytes: args.mode.orchestrator_args().max_upload_mb * 1024 * 1024,
         }
     }
 }
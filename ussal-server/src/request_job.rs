@@ -4,6 +4,7 @@ use axum::extract::ws::WebSocket;
 use axum::extract::{State, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use std::sync::Arc;
+use ussal_networking::hello::{Hello, Protocol};
 use ussal_networking::runner_protocol::{JobResponse, JobResponseType};
 
 pub async fn request_job(
@@ -13,19 +14,32 @@ pub async fn request_job(
     ws.on_upgrade(|stream| run_websocket(stream, state))
 }
 
-async fn run_websocket(stream: WebSocket, state: Arc<AppState>) {
+async fn run_websocket(mut stream: WebSocket, state: Arc<AppState>) {
     match &state.handler {
         crate::job_handler::HandlerState::Orchestrator(orch) => {
+            let hello = Hello::new(Protocol::Runner, crate::SOFTWARE);
+            if let Err(err) = ussal_networking::axum::accept_hello(&mut stream, &hello).await {
+                tracing::error!("{:?}", err.context("Rejected runner"));
+                return;
+            }
             let (tx, mut rx) = ussal_networking::axum::spawn_read_write_tasks(stream).await;
-            let machine_type = match rx.recv().await {
-                Some(JobResponse {
-                    ty: JobResponseType::Handshake { machine_type },
+            let (machine_type, cached_binaries) = match rx.recv().await {
+                Some(Ok(JobResponse {
+                    ty:
+                        JobResponseType::Handshake {
+                            machine_type,
+                            cached_binaries,
+                        },
                     ..
-                }) => machine_type,
-                Some(x) => {
+                })) => (machine_type, cached_binaries),
+                Some(Ok(x)) => {
                     tracing::error!("Expected handshake but was {x:?}");
                     return;
                 }
+                Some(Err(err)) => {
+                    tracing::error!("Expected handshake but failed to receive it: {err}");
+                    return;
+                }
                 None => {
                     tracing::error!("Expected handshake but no message was received");
                     return;
@@ -36,6 +50,7 @@ async fn run_websocket(stream: WebSocket, state: Arc<AppState>) {
                     tx,
                     rx,
                     machine_type,
+                    cached_binaries: cached_binaries.into_iter().collect(),
                 })
                 .unwrap();
         }
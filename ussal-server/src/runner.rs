@@ -1,17 +1,50 @@
-use crate::{cli::SandboxMode, system::run_sandboxed_binary};
+use crate::bench_output::{self, BenchHarness};
+use crate::binary_cache::BinaryCache;
+use crate::cli::SandboxMode;
+use crate::instructions::{self, InstructionCounter};
+use crate::system::{machine_fingerprint, run_sandboxed_binary};
 use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::time::timeout;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use ussal_networking::binary_hash::BinaryHash;
+use ussal_networking::hello::{Hello, Protocol};
+use ussal_networking::metric::{Metric, UnavailableMetric};
 use ussal_networking::runner_protocol::{
     BenchComplete, JobRequest, JobRequestType, JobResponse, JobResponseType,
 };
+use ussal_networking::transfer::ChunkReceiver;
+use ussal_networking::ReceiveError;
 use uuid::Uuid;
 
-pub async fn runner(sandbox_mode: SandboxMode, address: &str, machine_type: &str) {
+pub async fn runner(
+    sandbox_mode: SandboxMode,
+    address: &str,
+    machine_type: &str,
+    binary_cache_mb: u64,
+    binary_cache_dir: Option<PathBuf>,
+) {
+    // A dedicated subdirectory, so that a directory also used for other things, e.g. the home directory, is never touched
+    let cache_dir = binary_cache_dir
+        .unwrap_or_else(|| {
+            dirs_next::cache_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("UssalRunner")
+        })
+        .join("ussal-binary-cache");
+    let mut cache = match BinaryCache::new(cache_dir, binary_cache_mb * 1024 * 1024) {
+        Ok(cache) => cache,
+        Err(error) => {
+            tracing::error!("{:?}", error.context("Failed to create binary cache"));
+            return;
+        }
+    };
+    let instruction_counter = Arc::new(detect_instruction_counter());
     loop {
-        let stream = match connect(address).await {
+        let mut stream = match connect(address).await {
             Ok(stream) => stream,
             Err(error) => {
                 tracing::error!(
@@ -22,22 +55,95 @@ pub async fn runner(sandbox_mode: SandboxMode, address: &str, machine_type: &str
                 continue;
             }
         };
+        let hello = Hello::new(Protocol::Runner, crate::SOFTWARE);
+        if let Err(error) = ussal_networking::send_hello(&mut stream, &hello).await {
+            tracing::error!(
+                "{:?}",
+                error.context("Orchestrator refused the runner, retrying in 60s")
+            );
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        }
         let (tx, mut rx) =
             ussal_networking::spawn_read_write_tasks::<JobResponse, JobRequest>(stream).await;
         tx.send(JobResponse {
             job_id: Uuid::new_v4(),
             ty: JobResponseType::Handshake {
                 machine_type: machine_type.to_owned(),
+                cached_binaries: cache.hashes(),
             },
         })
         .unwrap();
-        if let Some(request) = rx.recv().await {
-            tracing::info!("running job: {} {:?}", request.job_id, request.ty);
-            let response = run_job_request(sandbox_mode, &request);
-            tx.send(response).unwrap();
-        } else {
-            tracing::error!("Connection was killed, retrying in 60s");
-            tokio::time::sleep(Duration::from_secs(60)).await;
+
+        // A binary the runner does not have is sent in chunks ahead of the job that runs it.
+        // Binaries only come from the orchestrator, which already limits how large a binary clients may upload.
+        let mut receiver = ChunkReceiver::new(u64::MAX);
+        let mut received = None;
+        loop {
+            match rx.recv().await {
+                Some(Ok(JobRequest {
+                    job_id,
+                    ty: JobRequestType::BinaryChunk(chunk),
+                    ..
+                })) => {
+                    let ty = match receiver.receive(chunk) {
+                        Ok(progress) => {
+                            if receiver.is_complete() {
+                                let binary =
+                                    std::mem::replace(&mut receiver, ChunkReceiver::new(u64::MAX))
+                                        .into_binary();
+                                if let Err(err) = cache.insert(progress.binary_hash, &binary) {
+                                    tracing::warn!("{:?}", err.context("Failed to cache binary"));
+                                }
+                                received = Some((progress.binary_hash, binary));
+                            }
+                            JobResponseType::BinaryChunkReceived(progress)
+                        }
+                        Err(err) => {
+                            receiver = ChunkReceiver::new(u64::MAX);
+                            JobResponseType::Error(err)
+                        }
+                    };
+                    tx.send(JobResponse { job_id, ty }).unwrap();
+                }
+                Some(Ok(request)) => {
+                    tracing::info!("running job: {} {:?}", request.job_id, request.ty);
+                    let job_id = request.job_id;
+                    let response =
+                        match load_binary(&mut cache, received.take(), request.binary_hash) {
+                            // Run off the async workers so that the connection keeps answering heartbeats during long benches
+                            Ok(binary) => {
+                                let instruction_counter = instruction_counter.clone();
+                                tokio::task::spawn_blocking(move || {
+                                    run_job_request(
+                                        sandbox_mode,
+                                        &instruction_counter,
+                                        &request,
+                                        &binary,
+                                    )
+                                })
+                                .await
+                                .unwrap()
+                            }
+                            Err(err) => JobResponse {
+                                job_id,
+                                ty: JobResponseType::Error(format!("{err:?}")),
+                            },
+                        };
+                    tx.send(response).unwrap();
+                    break;
+                }
+                Some(Err(ReceiveError::Closed)) | None => {
+                    tracing::error!("Connection was killed, retrying in 60s");
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    break;
+                }
+                Some(Err(err)) => {
+                    tracing::error!("Failed to receive job, retrying in 60s: {err}");
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    break;
+                }
+            }
         }
     }
 }
@@ -50,34 +156,89 @@ async fn connect(uri: &str) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>
     Ok(ws_stream)
 }
 
-pub fn run_job_request(sandbox_mode: SandboxMode, request: &JobRequest) -> JobResponse {
+/// The binary sent ahead of the job, or the cached binary when the orchestrator knew that the runner already had it
+fn load_binary(
+    cache: &mut BinaryCache,
+    received: Option<(BinaryHash, Vec<u8>)>,
+    hash: BinaryHash,
+) -> Result<Vec<u8>> {
+    match received {
+        Some((received_hash, binary)) if received_hash == hash => Ok(binary),
+        _ => cache
+            .get(hash)?
+            .ok_or_else(|| anyhow!("Binary {hash} was not sent and is not cached")),
+    }
+}
+
+/// Logs which tool will count instructions, or why instructions will be unavailable
+pub fn detect_instruction_counter() -> Result<InstructionCounter> {
+    let counter = InstructionCounter::detect();
+    match &counter {
+        Ok(counter) => tracing::info!("Counting instructions with {counter:?}"),
+        Err(err) => tracing::warn!("{err}"),
+    }
+    counter
+}
+
+pub fn run_job_request(
+    sandbox_mode: SandboxMode,
+    instruction_counter: &Result<InstructionCounter>,
+    request: &JobRequest,
+    binary: &[u8],
+) -> JobResponse {
+    JobResponse {
+        job_id: request.job_id,
+        ty: run_job_request_inner(sandbox_mode, instruction_counter, request, binary)
+            .unwrap_or_else(|err| JobResponseType::Error(format!("{err:?}"))),
+    }
+}
+
+fn run_job_request_inner(
+    sandbox_mode: SandboxMode,
+    instruction_counter: &Result<InstructionCounter>,
+    request: &JobRequest,
+    binary: &[u8],
+) -> Result<JobResponseType> {
     // TODO: run as ussal-sandbox
     let binary_path = std::env::temp_dir().join("binary-under-test");
     std::fs::remove_file(&binary_path).ok();
-    std::fs::write(&binary_path, &request.binary).unwrap();
+    std::fs::write(&binary_path, binary)
+        .map_err(|e| anyhow!(e).context(format!("Failed to write {binary_path:?}")))?;
     #[cfg(unix)]
     std::fs::set_permissions(
         &binary_path,
         std::os::unix::fs::PermissionsExt::from_mode(0o100),
-    )
-    .unwrap();
+    )?;
+
+    // The bench binary appends its results to this file, so it needs to exist and be writable by the sandbox user.
+    let results_path = std::env::temp_dir().join("bench-results.jsonl");
+    std::fs::remove_file(&results_path).ok();
+    std::fs::write(&results_path, [])
+        .map_err(|e| anyhow!(e).context(format!("Failed to create {results_path:?}")))?;
+    #[cfg(unix)]
+    std::fs::set_permissions(
+        &results_path,
+        std::os::unix::fs::PermissionsExt::from_mode(0o666),
+    )?;
 
     let binary_path = binary_path.to_str().unwrap();
+    let results_path_str = results_path.to_str().unwrap();
 
     match &request.ty {
         JobRequestType::ListBenches => {
             // `cargo bench` automatically adds in the `--bench`
-            let output =
-                run_sandboxed_binary(sandbox_mode, binary_path, &["--bench", "--list"]).unwrap();
+            let output = run_sandboxed_binary(
+                sandbox_mode,
+                binary_path,
+                &["--bench", "--list"],
+                results_path_str,
+            )?;
 
             let benches: Vec<String> = output
                 .lines()
                 .filter_map(|line| line.strip_suffix(": benchmark").map(|x| x.to_owned()))
                 .collect();
-            JobResponse {
-                job_id: request.job_id,
-                ty: JobResponseType::ListBenches(benches),
-            }
+            Ok(JobResponseType::ListBenches(benches))
         }
         JobRequestType::RunBench { bench_name } => {
             let output = run_sandboxed_binary(
@@ -90,34 +251,53 @@ pub fn run_job_request(sandbox_mode: SandboxMode, request: &JobRequest) -> JobRe
                     "--noplot",
                     "--discard-baseline",
                 ],
-            )
-            .unwrap();
+                results_path_str,
+            )?;
+            let results = std::fs::read_to_string(&results_path)
+                .map_err(|e| anyhow!(e).context(format!("Failed to read {results_path:?}")))?;
 
-            let mut wall_time: Option<f32> = None;
-            // This logic is so brittle, but we plan to replace criterion later anyway.
-            for line in output.lines() {
-                if line.contains("time: ") {
-                    let mut iter = line.split('[');
-                    iter.next().unwrap(); // skip first one
-
-                    let mut words = iter.next().unwrap().split_whitespace();
-                    words.next().unwrap(); // skip these
-                    words.next().unwrap(); // skip these
-                    let value = words.next().unwrap();
-                    wall_time = Some(
-                        value
-                            .parse()
-                            .unwrap_or_else(|_| panic!("Failed to parse {value} as float")),
-                    );
-                }
+            let bench = bench_output::parse_run_bench(bench_name, &results, &output)?;
+            let mut metrics = bench.metrics;
+            let mut unavailable = vec![];
+            let instructions = match bench.harness {
+                BenchHarness::UssalBench => match instruction_counter {
+                    Ok(counter) => instructions::count(
+                        counter,
+                        sandbox_mode,
+                        binary_path,
+                        bench_name,
+                        results_path_str,
+                        bench.wall_time_nanoseconds,
+                    )
+                    .map_err(|err| format!("{err:?}")),
+                    Err(err) => Err(format!("{err:?}")),
+                },
+                BenchHarness::Criterion => Err(
+                    "Instructions can only be counted for benches using the ussal-bench harness"
+                        .to_owned(),
+                ),
+            };
+            match instructions {
+                Ok(value) => metrics.push(Metric {
+                    name: "instructions".to_owned(),
+                    unit: String::new(),
+                    value,
+                    samples: None,
+                }),
+                Err(reason) => unavailable.push(UnavailableMetric {
+                    name: "instructions".to_owned(),
+                    reason,
+                }),
             }
 
-            let wall_time =
-                wall_time.unwrap_or_else(|| panic!("Did not find wall time in output: {output:?}"));
-            JobResponse {
-                job_id: request.job_id,
-                ty: JobResponseType::RunBench(BenchComplete { wall_time }),
-            }
+            Ok(JobResponseType::RunBench(BenchComplete {
+                metrics,
+                unavailable,
+                runner_fingerprint: machine_fingerprint(),
+            }))
         }
+        JobRequestType::BinaryChunk(_) => Err(anyhow!(
+            "Binary chunks are received by the runner rather than run"
+        )),
     }
 }
@@ -2,12 +2,22 @@ use crate::cli::{LogFormat, SandboxMode};
 use anyhow::{anyhow, Result};
 use subprocess::{Exec, Redirection};
 use tracing_appender::non_blocking::WorkerGuard;
+use ussal_bench::RESULTS_PATH_ENV;
 
 /// Runs a command and returns the output as a string.
 /// Both stderr and stdout are returned in the result.
 pub fn run_command(command: &str, args: &[&str]) -> Result<String> {
-    let data = Exec::cmd(command)
-        .args(args)
+    run_command_with_env(command, args, &[])
+}
+
+/// Runs a command with the provided environment variables set and returns the output as a string.
+/// Both stderr and stdout are returned in the result.
+pub fn run_command_with_env(command: &str, args: &[&str], env: &[(&str, &str)]) -> Result<String> {
+    let mut exec = Exec::cmd(command).args(args);
+    for (key, value) in env {
+        exec = exec.env(key, value);
+    }
+    let data = exec
         .stdout(Redirection::Pipe)
         .stderr(Redirection::Merge)
         .capture()
@@ -28,14 +38,56 @@ pub fn run_command(command: &str, args: &[&str]) -> Result<String> {
 
 /// Runs a binary in an nsjail and returns the output as a string.
 /// Both stderr and stdout are returned in the result.
+///
+/// The binary is given write access to the `results_path` file and its location is passed to the binary via the `USSAL_RESULTS_PATH` environment variable.
 pub fn run_sandboxed_binary(
     sandbox_mode: SandboxMode,
     command: &str,
     args: &[&str],
+    results_path: &str,
+) -> Result<String> {
+    run_wrapped_sandboxed_binary(sandbox_mode, Wrapper::None, command, args, results_path)
+}
+
+/// A tool that measures the sandboxed binary by running it
+#[derive(Clone, Copy)]
+pub enum Wrapper<'a> {
+    None,
+    /// The tool runs outside of the sandbox with the entire sandbox invocation appended to `args`.
+    Outside {
+        args: &'a [&'a str],
+    },
+    /// The tool runs inside of the sandbox with the binary and its arguments appended to `args`.
+    /// `args[0]` must be an absolute path to the tool.
+    Inside {
+        args: &'a [&'a str],
+        /// Extra paths the tool needs to read
+        read_only: &'a [&'a str],
+        /// Extra paths the tool needs to write
+        writable: &'a [&'a str],
+    },
+}
+
+/// The same as `run_sandboxed_binary` but the binary is run by the `wrapper` tool.
+pub fn run_wrapped_sandboxed_binary(
+    sandbox_mode: SandboxMode,
+    wrapper: Wrapper<'_>,
+    command: &str,
+    args: &[&str],
+    results_path: &str,
 ) -> Result<String> {
-    match sandbox_mode {
+    let mut sandboxed = vec![];
+    if let Wrapper::Inside { args, .. } = wrapper {
+        sandboxed.extend(args);
+    }
+    sandboxed.push(command);
+    sandboxed.extend(args);
+
+    let results_env = format!("{RESULTS_PATH_ENV}={results_path}");
+    let (mut full, env) = match sandbox_mode {
         SandboxMode::NsjailComplete => {
             let mut nsjail_args = vec![
+                "nsjail",
                 "--really_quiet",
                 "--mode",
                 "o",
@@ -52,14 +104,71 @@ pub fn run_sandboxed_binary(
                 "/dev/urandom",
                 "-R",
                 command,
-                "--",
-                command,
+                "-B",
+                results_path,
+                "-E",
+                &results_env,
             ];
-            nsjail_args.extend(args);
-            run_command("nsjail", &nsjail_args)
+            if let Wrapper::Inside {
+                args,
+                read_only,
+                writable,
+            } = wrapper
+            {
+                nsjail_args.extend(["-R", args[0]]);
+                for path in read_only {
+                    nsjail_args.extend(["-R", path]);
+                }
+                for path in writable {
+                    nsjail_args.extend(["-B", path]);
+                }
+            }
+            nsjail_args.push("--");
+            nsjail_args.extend(sandboxed);
+            (nsjail_args, vec![])
         }
-        SandboxMode::None => run_command(command, args),
+        SandboxMode::None => (sandboxed, vec![(RESULTS_PATH_ENV, results_path)]),
+    };
+    if let Wrapper::Outside { args } = wrapper {
+        full.splice(0..0, args.iter().copied());
     }
+
+    run_command_with_env(full[0], &full[1..], &env)
+}
+
+/// A human readable description of the hardware and OS of this machine.
+/// Used to tell apart measurements from runners of the same machine type that are not quite identical.
+pub fn machine_fingerprint() -> String {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    // x86 reports `model name` per core while raspberry pis report `Model` for the whole board
+    let cpu = cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| matches!(key.trim(), "model name" | "Model"))
+        .map(|(_, value)| value.trim().to_owned())
+        .unwrap_or_else(|| std::env::consts::ARCH.to_owned());
+    let cores = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1);
+    let memory = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            let kilobytes: u64 = meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix("MemTotal:"))?
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse()
+                .ok()?;
+            Some(format!(", {}MiB", kilobytes / 1024))
+        })
+        .unwrap_or_default();
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| format!(" {}", release.trim()))
+        .unwrap_or_default();
+
+    format!("{cpu} x{cores}{memory}, {}{kernel}", std::env::consts::OS)
 }
 
 pub fn init_tracing(format: LogFormat) -> WorkerGuard {
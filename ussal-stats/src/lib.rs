@@ -0,0 +1,51 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all, rust_2018_idioms)]
+
+//! Summary statistics shared by the ussal-bench harness and ussal-archive.
+//! Kept in a crate of their own so that the archive format does not depend on the harness, while both still compute the same statistics.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    /// Returns None if there are no samples
+    pub fn from_samples(samples: &[f64]) -> Option<Summary> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let len = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / len as f64;
+        let median = if len % 2 == 1 {
+            sorted[len / 2]
+        } else {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
+        };
+        let std_dev = if len > 1 {
+            let variance =
+                sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (len - 1) as f64;
+            variance.sqrt()
+        } else {
+            0.0
+        };
+
+        Some(Summary {
+            mean,
+            median,
+            std_dev,
+            min: sorted[0],
+            max: sorted[len - 1],
+        })
+    }
+}
@@ -3,7 +3,9 @@ use eframe::{
     emath::Align2,
     epaint::Color32,
 };
-use egui_plot::{Legend, Line, Plot, PlotPoint, PlotPoints, Text};
+use egui_plot::{HLine, Legend, Line, LineStyle, Plot, PlotPoint, PlotPoints, Text};
+use std::collections::BTreeSet;
+use ussal_archive::query::{KeyPredicate, Query};
 use ussal_archive::{Bench, BenchArchive};
 
 struct FilterValue {
@@ -18,47 +20,53 @@ struct FilterKey {
 
 pub struct App {
     archive: BenchArchive,
+    /// Drawn underneath the archive, e.g. the main branch history when viewing a PR
+    baseline: Option<BenchArchive>,
+    /// The name of a baseline pinned in the archive, drawn as a horizontal line per series
+    pinned_baseline: Option<String>,
+    /// Retired benches are no longer being run, so are hidden by default
+    show_retired: bool,
     filter_keys: Vec<FilterKey>,
     bench_names: Vec<String>,
 }
 
 impl App {
     pub fn new(_cc: &eframe::CreationContext<'_>, archive: BenchArchive) -> Self {
-        let mut filter_keys: Vec<FilterKey> = vec![];
-        for bench in &archive.benches {
-            for (key, value) in &bench.keys {
-                if let Some(filter_key) = filter_keys.iter_mut().find(|x| &x.name == key) {
-                    if !filter_key.values.iter().any(|x| &x.name == value) {
-                        filter_key.values.push(FilterValue {
-                            name: value.to_owned(),
-                            show: true,
-                        })
-                    }
-                } else {
-                    filter_keys.push(FilterKey {
-                        name: key.to_owned(),
-                        values: vec![FilterValue {
-                            name: value.to_owned(),
-                            show: true,
-                        }],
+        let filter_keys = archive
+            .key_values()
+            .into_iter()
+            .map(|(key, values)| FilterKey {
+                name: key.to_owned(),
+                values: values
+                    .into_iter()
+                    .map(|value| FilterValue {
+                        name: value.to_owned(),
+                        show: true,
                     })
-                }
-            }
-        }
-        let mut bench_names = vec![];
-        for bench in &archive.benches {
-            if !bench_names.contains(&bench.name) {
-                bench_names.push(bench.name.clone());
-            }
-        }
-        bench_names.sort();
+                    .collect(),
+            })
+            .collect();
+        let bench_names: BTreeSet<String> = archive
+            .benches
+            .iter()
+            .map(|bench| bench.name.clone())
+            .collect();
+        let bench_names = bench_names.into_iter().collect();
 
         Self {
             archive,
+            baseline: None,
+            pinned_baseline: None,
+            show_retired: false,
             filter_keys,
             bench_names,
         }
     }
+
+    pub fn with_baseline(mut self, baseline: BenchArchive) -> Self {
+        self.baseline = Some(baseline);
+        self
+    }
 }
 
 impl eframe::App for App {
@@ -76,6 +84,21 @@ impl eframe::App for App {
         });
 
         egui::SidePanel::left("side_panel").show(ctx, |ui| {
+            ui.checkbox(&mut self.show_retired, "Show retired benches");
+            if !self.archive.baselines.is_empty() {
+                egui::ComboBox::from_label("Pinned baseline")
+                    .selected_text(self.pinned_baseline.as_deref().unwrap_or("None"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.pinned_baseline, None, "None");
+                        for baseline in &self.archive.baselines {
+                            ui.selectable_value(
+                                &mut self.pinned_baseline,
+                                Some(baseline.name.clone()),
+                                &baseline.name,
+                            );
+                        }
+                    });
+            }
             for filter_key in &mut self.filter_keys {
                 ui.heading(&filter_key.name);
                 for value in &mut filter_key.values {
@@ -89,7 +112,7 @@ impl eframe::App for App {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 ui.horizontal_wrapped(|ui| {
                     for (i, name) in self.bench_names.iter().enumerate() {
-                        plot_bench(ui, i as i32, name, &self.archive.benches, &self.filter_keys);
+                        self.plot_bench(ui, i as i32, name);
                     }
                 })
             });
@@ -105,71 +128,176 @@ impl eframe::App for App {
     }
 }
 
-fn plot_bench(
-    ui: &mut egui::Ui,
-    id: i32,
-    name: &str,
-    benches: &[Bench],
-    filter_keys: &[FilterKey],
-) {
-    //ui.vertical(|ui| {
-    //ui.label(&bench.name);
-    let plot = Plot::new(id)
-        .legend(Legend::default())
-        .width(500.0)
-        .height(250.0)
-        .allow_scroll(false);
-
-    if benches.iter().any(|bench| show_bench(filter_keys, bench)) {
-        // TODO: add_sized used to work around https://github.com/emilk/egui/issues/3449
-        ui.add_sized([500.0, 250.0], |ui: &mut egui::Ui| {
-            plot.show(ui, |plot_ui| {
-                let mut legend_i = 0;
-                for bench in benches.iter() {
-                    if bench.name == name && show_bench(filter_keys, bench) {
-                        let line = PlotPoints::new(
-                            bench
-                                .measurements
-                                .iter()
-                                .enumerate()
-                                .map(|(i, x)| [i as f64, x.value as f64])
-                                .collect(),
-                        );
-
-                        plot_ui.line(Line::new(line).color(COLORS[legend_i % COLORS.len()]).name(
-                            format!(
-                                "{}-{}",
-                                bench.keys.get("machine").unwrap(),
-                                bench.keys.get("type").unwrap(),
-                            ),
-                        ));
-                        legend_i += 1;
-                    }
+/// A series to draw, `offset` is the x position of its first measurement
+struct PlotLine<'a> {
+    name: String,
+    offset: usize,
+    bench: &'a Bench,
+    baseline: bool,
+}
+
+impl App {
+    fn plot_bench(&self, ui: &mut egui::Ui, id: i32, name: &str) {
+        let pinned_baseline = self
+            .pinned_baseline
+            .as_deref()
+            .and_then(|name| self.archive.baseline(name));
+
+        //ui.vertical(|ui| {
+        //ui.label(&bench.name);
+
+        let mut query = Query::default().with_name(name);
+        if !self.show_retired {
+            query = query.without_retired();
+        }
+        // Matches the series whose key values have not been toggled off in the side panel
+        let mut filter = Query::default();
+        for filter_key in &self.filter_keys {
+            let hidden = filter_key
+                .values
+                .iter()
+                .filter(|value| !value.show)
+                .map(|value| value.name.clone())
+                .collect();
+            filter = filter.with_key(&filter_key.name, KeyPredicate::NoneOf(hidden));
+        }
+
+        // When a baseline is loaded, each series is drawn as a continuation of the same series in the baseline.
+        let mut lines = vec![];
+        for series in self.archive.query(&query) {
+            let bench = series.bench;
+            let baseline_bench = self
+                .baseline
+                .as_ref()
+                .and_then(|baseline| baseline.find(bench));
+            let offset = match baseline_bench {
+                Some(baseline_bench) => {
+                    lines.push(PlotLine {
+                        name: format!("{} (baseline)", line_name(bench)),
+                        offset: 0,
+                        bench: baseline_bench,
+                        baseline: true,
+                    });
+                    baseline_bench.measurements.len()
                 }
-                plot_ui.text(
-                    Text::new(
-                        PlotPoint::new(0.0, 0.0),
-                        RichText::new(format!(" {}", name)).size(17.0),
-                    )
-                    .anchor(Align2::LEFT_BOTTOM)
-                    .color(Color32::WHITE),
-                );
+                None => 0,
+            };
+            lines.push(PlotLine {
+                name: line_name(bench),
+                offset,
+                bench,
+                baseline: false,
+            });
+        }
+
+        // The commit of each measurement, keyed by line name, so that hovering a point shows which change it came from.
+        let commits: Vec<(String, usize, Vec<Option<String>>)> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line.name.clone(),
+                    line.offset,
+                    line.bench
+                        .measurements
+                        .iter()
+                        .map(|x| x.metadata.as_ref().and_then(|x| x.commit.clone()))
+                        .collect(),
+                )
             })
-            .response
-        });
+            .collect();
+        let plot = Plot::new(id)
+            .legend(Legend::default())
+            .width(500.0)
+            .height(250.0)
+            .allow_scroll(false)
+            .label_formatter(move |line, point| {
+                let commit = commits
+                    .iter()
+                    .find(|(name, _, _)| name == line)
+                    .and_then(|(_, offset, commits)| {
+                        commits.get((point.x.round() as usize).checked_sub(*offset)?)
+                    })
+                    .cloned()
+                    .flatten();
+                match commit {
+                    Some(commit) => format!("{line}\n{:.2}\ncommit {commit:.10}", point.y),
+                    None => format!("{line}\n{:.2}", point.y),
+                }
+            });
+
+        if lines.iter().any(|line| filter.matches(line.bench)) {
+            // TODO: add_sized used to work around https://github.com/emilk/egui/issues/3449
+            ui.add_sized([500.0, 250.0], |ui: &mut egui::Ui| {
+                plot.show(ui, |plot_ui| {
+                    let mut legend_i = 0;
+                    for line in &lines {
+                        if filter.matches(line.bench) {
+                            let points = PlotPoints::new(
+                                line.bench
+                                    .measurements
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(i, x)| [(line.offset + i) as f64, x.value as f64])
+                                    .collect(),
+                            );
+
+                            // A baseline shares the color of the series that follows it
+                            let mut plot_line = Line::new(points)
+                                .color(COLORS[legend_i % COLORS.len()])
+                                .name(&line.name);
+                            if line.baseline {
+                                plot_line = plot_line.style(LineStyle::dashed_loose());
+                            } else {
+                                if let Some(pinned) = pinned_baseline {
+                                    let pinned_measurement = pinned
+                                        .benches
+                                        .iter()
+                                        .find(|x| {
+                                            x.name == line.bench.name && x.keys == line.bench.keys
+                                        })
+                                        .and_then(|x| x.measurements.first());
+                                    if let Some(pinned_measurement) = pinned_measurement {
+                                        plot_ui.hline(
+                                            HLine::new(pinned_measurement.value as f64)
+                                                .color(COLORS[legend_i % COLORS.len()])
+                                                .style(LineStyle::dotted_dense())
+                                                .name(format!("{} ({})", line.name, pinned.name)),
+                                        );
+                                    }
+                                }
+                                legend_i += 1;
+                            }
+                            plot_ui.line(plot_line);
+                        }
+                    }
+                    plot_ui.text(
+                        Text::new(
+                            PlotPoint::new(0.0, 0.0),
+                            RichText::new(format!(" {}", name)).size(17.0),
+                        )
+                        .anchor(Align2::LEFT_BOTTOM)
+                        .color(Color32::WHITE),
+                    );
+                })
+                .response
+            });
+        }
+        // });
     }
-    // });
 }
 
-fn show_bench(filter_keys: &[FilterKey], bench: &Bench) -> bool {
-    for (key, value) in &bench.keys {
-        let filter_key = filter_keys.iter().find(|x| &x.name == key).unwrap();
-        let filter_value = filter_key.values.iter().find(|x| &x.name == value).unwrap();
-        if !filter_value.show {
-            return false;
-        }
+/// Series imported from other tools may lack the `machine` or `type` key, the bench name is used if both are missing
+fn line_name(bench: &Bench) -> String {
+    let name = ["machine", "type"]
+        .iter()
+        .filter_map(|key| bench.keys.get(*key).map(|x| x.as_str()))
+        .collect::<Vec<_>>()
+        .join("-");
+    if name.is_empty() {
+        bench.name.clone()
+    } else {
+        name
     }
-    true
 }
 
 // TODO: MORE and maybe BETTER colors